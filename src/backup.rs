@@ -0,0 +1,390 @@
+use crate::commit;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+/// The name of the manifest inside a backup directory.
+const MANIFEST: &str = "decoreco-manifest.jsonl";
+
+/// One original that was moved into a backup directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Where the original was, as an absolute path.
+    pub original: PathBuf,
+    /// Where the original is now, relative to the backup directory.
+    pub backup: PathBuf,
+    /// The file that replaced it, which is the same as `original` unless it was an image.
+    pub replacement: PathBuf,
+    /// When it was replaced, in seconds since the unix epoch.
+    pub time: u64,
+}
+
+/// What happened to one entry when restoring or purging.
+pub type Outcome<T> = (Entry, Result<T, String>);
+
+/// A backup directory that originals get moved into instead of being thrown away.
+///
+/// Originals go into a tree mirroring their absolute paths, so `/home/me/a.mp4` ends up
+/// at `<dir>/home/me/a.mp4`, and every one is listed in a manifest at the top of the tree.
+/// The manifest is only appended to once the original has actually been replaced.
+pub struct Backups {
+    dir: PathBuf,
+    manifest: Mutex<File>,
+}
+
+/// An original that's been copied into the backup tree but not recorded yet.
+pub struct Kept {
+    original: PathBuf,
+    backup: PathBuf,
+}
+
+impl Backups {
+    /// Opens a backup directory, creating it if it doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The path to the backup directory.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let manifest = File::options()
+            .create(true)
+            .append(true)
+            .open(dir.join(MANIFEST))?;
+        Ok(Self {
+            dir: std::path::absolute(dir)?,
+            manifest: Mutex::new(manifest),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Puts a copy of an original into the backup tree before it gets replaced.
+    ///
+    /// It's hard linked if it can be, so it doesn't take any extra space, and copied with
+    /// its timestamps and permissions otherwise. The original isn't touched.
+    ///
+    /// # Arguments
+    ///
+    /// * `original` - The path to the file that's about to be replaced.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Kept)`, which has to be passed to `record` once the original is replaced,
+    ///   or `discard`ed if it wasn't.
+    /// * `Err(io::Error)` if it couldn't be copied.
+    pub fn keep(&self, original: &Path) -> io::Result<Kept> {
+        let original = std::path::absolute(original)?;
+        // only the normal components, so the root and any drive prefix are dropped
+        let relative: PathBuf = original
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        let mut backup = self.dir.join(&relative);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // the same file can be backed up by more than one run, so number the later ones
+        let mut n = 0;
+        loop {
+            match commit::link_or_copy(&original, &backup) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    n += 1;
+                    let mut name = relative.clone().into_os_string();
+                    name.push(format!(".{n}"));
+                    backup = self.dir.join(name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Kept { original, backup })
+    }
+
+    /// Adds a kept original to the manifest, once whatever replaced it is in place.
+    ///
+    /// # Arguments
+    ///
+    /// * `kept` - What `keep` returned.
+    /// * `replacement` - The path of the file that replaced the original.
+    pub fn record(&self, kept: Kept, replacement: &Path) -> io::Result<()> {
+        let entry = Entry {
+            replacement: std::path::absolute(replacement)?,
+            backup: kept
+                .backup
+                .strip_prefix(&self.dir)
+                .map_err(io::Error::other)?
+                .to_path_buf(),
+            original: kept.original,
+            time: now(),
+        };
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        let mut manifest = self.manifest.lock().expect("poisoned");
+        manifest.write_all(&line)?;
+        manifest.sync_data()
+    }
+}
+
+impl Kept {
+    /// Throws away a kept original that didn't end up being replaced after all.
+    pub fn discard(self) -> io::Result<()> {
+        fs::remove_file(self.backup)
+    }
+}
+
+/// Reads every entry in a backup directory's manifest.
+///
+/// # Arguments
+///
+/// * `dir` - The path to the backup directory.
+fn read_manifest(dir: &Path) -> Result<Vec<Entry>, String> {
+    let path = dir.join(MANIFEST);
+    let file = File::open(&path).map_err(|e| format!("failed to open {}: {e}", path.display()))?;
+    BufReader::new(file)
+        .lines()
+        .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()))
+        .map(|l| {
+            let l = l.map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+            serde_json::from_str(&l).map_err(|e| format!("{} is corrupt: {e}", path.display()))
+        })
+        .collect()
+}
+
+/// Replaces a backup directory's manifest with the given entries.
+///
+/// # Arguments
+///
+/// * `dir` - The path to the backup directory.
+/// * `entries` - The entries that are still in the backup tree.
+fn write_manifest(dir: &Path, entries: &[Entry]) -> Result<(), String> {
+    let path = dir.join(MANIFEST);
+    let write = || -> io::Result<()> {
+        let mut file = NamedTempFile::new_in(dir)?;
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry).map_err(io::Error::other)?;
+            file.write_all(b"\n")?;
+        }
+        file.as_file().sync_all()?;
+        file.persist(&path).map_err(|e| e.error)?;
+        Ok(())
+    };
+    write().map_err(|e| format!("failed to update {}: {e}", path.display()))
+}
+
+/// Puts originals from a backup directory back where they came from.
+///
+/// The backup is moved over whatever's there now, and if the original was an image,
+/// the jxl that replaced it is removed. Restored originals are taken out of the manifest.
+///
+/// # Arguments
+///
+/// * `dir` - The path to the backup directory.
+/// * `only` - Only restore originals at these paths. Everything is restored if it's empty.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Outcome<()>>)` with how restoring each original went.
+/// * `Err(String)` if the manifest couldn't be read or written.
+pub fn restore(dir: &Path, only: &[PathBuf]) -> Result<Vec<Outcome<()>>, String> {
+    let only = only
+        .iter()
+        .map(std::path::absolute)
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+    let mut kept = Vec::new();
+    let mut results = Vec::new();
+    // newest first, so if a file was replaced more than once the oldest version ends up back
+    let mut entries = read_manifest(dir)?;
+    entries.reverse();
+    for entry in entries {
+        if !only.is_empty() && !only.contains(&entry.original) {
+            kept.push(entry);
+            continue;
+        }
+        let backup = dir.join(&entry.backup);
+        let res = match commit::replace(&backup, &entry.original, Some(&backup), None) {
+            // the original's back either way, it just might not survive a crash
+            Ok(()) | Err(commit::Error::Replaced(_)) => Ok(()),
+            Err(commit::Error::Untouched(e)) => Err(e),
+        }
+        .and_then(|()| {
+            if entry.replacement == entry.original {
+                Ok(())
+            } else {
+                fs::remove_file(&entry.replacement).or_else(|e| {
+                    // it's fine if it's already gone
+                    if e.kind() == io::ErrorKind::NotFound {
+                        Ok(())
+                    } else {
+                        Err(e)
+                    }
+                })
+            }
+        })
+        .map_err(|e| e.to_string());
+        if res.is_err() {
+            kept.push(entry.clone());
+        }
+        remove_empty_dirs(dir, &backup);
+        results.push((entry, res));
+    }
+    kept.reverse();
+    write_manifest(dir, &kept)?;
+    results.reverse();
+    Ok(results)
+}
+
+/// Deletes originals from a backup directory, making their replacements final.
+///
+/// # Arguments
+///
+/// * `dir` - The path to the backup directory.
+/// * `older_than` - Only delete originals that were replaced at least this long ago.
+///
+/// # Returns
+///
+/// * `Ok(Vec<Outcome<u64>>)` with the size of each deleted original,
+///   or why it couldn't be deleted.
+/// * `Err(String)` if the manifest couldn't be read or written.
+pub fn purge(dir: &Path, older_than: Duration) -> Result<Vec<Outcome<u64>>, String> {
+    let cutoff = now().saturating_sub(older_than.as_secs());
+    let mut kept = Vec::new();
+    let mut results = Vec::new();
+    for entry in read_manifest(dir)? {
+        if entry.time > cutoff {
+            kept.push(entry);
+            continue;
+        }
+        let backup = dir.join(&entry.backup);
+        let res = fs::metadata(&backup)
+            .and_then(|m| fs::remove_file(&backup).map(|()| m.len()))
+            .or_else(|e| {
+                // someone already cleaned it up by hand
+                if e.kind() == io::ErrorKind::NotFound {
+                    Ok(0)
+                } else {
+                    Err(e)
+                }
+            })
+            .map_err(|e| e.to_string());
+        if res.is_err() {
+            kept.push(entry.clone());
+        }
+        remove_empty_dirs(dir, &backup);
+        results.push((entry, res));
+    }
+    write_manifest(dir, &kept)?;
+    Ok(results)
+}
+
+/// Removes the directories leading to a file in the backup tree, as long as they're empty.
+fn remove_empty_dirs(root: &Path, file: &Path) {
+    for dir in file.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Parses an age like `30d`, `12h` or `2w`.
+///
+/// # Arguments
+///
+/// * `s` - The age as given on the command line.
+///
+/// # Returns
+///
+/// * `Ok(Duration)` if the age is valid.
+/// * `Err(String)` if it isn't, with the error message.
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let err = || format!("{s} is not a valid age, try e.g. 30d, 12h or 2w");
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?);
+    let number: u64 = number.parse().map_err(|_| err())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(err()),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(err)
+}
+
+/// The current time in seconds since the unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_age("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_age("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_age("0d"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_bad_ages() {
+        for bad in [
+            "",
+            "30",
+            "d",
+            "30 d",
+            "-1d",
+            "1.5d",
+            "30D",
+            "30days",
+            "1y",
+            // too big to fit, before and after it's turned into seconds
+            "99999999999999999999s",
+            "99999999999999999w",
+        ] {
+            assert!(parse_age(bad).is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_read_only_original() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let original = tmp.path().join("a.mp4");
+        fs::write(&original, b"original").unwrap();
+        fs::set_permissions(&original, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let backups = Backups::open(&tmp.path().join("bk")).unwrap();
+        let kept = backups.keep(&original).unwrap();
+        let encoded = tmp.path().join("encoded.mp4");
+        fs::write(&encoded, b"encoded").unwrap();
+        commit::replace(&encoded, &original, Some(&original), None).unwrap();
+        backups.record(kept, &original).unwrap();
+
+        let results = restore(backups.dir(), &[]).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1, Ok(()));
+        assert_eq!(fs::read(&original).unwrap(), b"original");
+        let mode = fs::metadata(&original).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+        assert!(read_manifest(backups.dir()).unwrap().is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use std::process::Command;
 
 // parse the command line arguments
 use clap::{App, Arg, Shell, SubCommand};
-use man::{Author, Example, Flag, Manual, Opt, Section};
+use man::{Author, Env, Example, Flag, Manual, Opt, Section};
 use tempfile::Builder;
 
 pub fn cli() -> App<'static, 'static> {
@@ -43,6 +43,44 @@ Please contribute a guide for your favorite shell! "
         .subcommand(SubCommand::with_name("manpage").about("Opens our man page.").alias("info")
 
     )
+        // undo or finalize a run that used --keep-originals
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Put originals from a --keep-originals directory back")
+                .arg(
+                    Arg::with_name("backup-dir")
+                        .required(true)
+                        .takes_value(true)
+                        .index(1)
+                        .help("the directory given to --keep-originals"),
+                )
+                .arg(
+                    Arg::with_name("files")
+                        .takes_value(true)
+                        .multiple(true)
+                        .index(2)
+                        .help("only restore these files, by the path they were at"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("purge-backups")
+                .about("Delete originals from a --keep-originals directory")
+                .arg(
+                    Arg::with_name("backup-dir")
+                        .required(true)
+                        .takes_value(true)
+                        .index(1)
+                        .help("the directory given to --keep-originals"),
+                )
+                .arg(
+                    Arg::with_name("older-than")
+                        .long("older-than")
+                        .required(true)
+                        .takes_value(true)
+                        .validator(|e| crate::backup::parse_age(&e).map(|_| ()))
+                        .help("only delete originals replaced at least this long ago, e.g. 30d, 12h or 2w. 0s deletes everything"),
+                ),
+        )
         .arg(
             Arg::with_name("path")
                 .case_insensitive(true)
@@ -55,7 +93,103 @@ Please contribute a guide for your favorite shell! "
             Arg::with_name("dry-run")
                 .short("d")
                 .long("dry-run")
-                .help("don't actually do anything"),
+                .help("show what would be done to each file without encoding anything"),
+        )
+        // and a trial option that encodes but doesn't touch the originals
+        .arg(
+            Arg::with_name("trial")
+                .long("trial")
+                .alias("no-replace")
+                .conflicts_with("dry-run")
+                .help("encode everything and report the savings, but keep the originals"),
+        )
+        // re-encode files that are already in the target codecs
+        .arg(
+            Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("re-encode files even if they're already in the target codecs"),
+        )
+        // skip the slowest part of checking encodes
+        .arg(
+            Arg::with_name("no-decode-check")
+                .long("no-decode-check")
+                .help("don't decode each encode all the way through before replacing the original"),
+        )
+        // don't carry over the original's timestamps and such
+        .arg(
+            Arg::with_name("no-preserve")
+                .long("no-preserve")
+                .help("don't copy timestamps, permissions, ownership and xattrs from the originals"),
+        )
+        // move originals somewhere instead of throwing them away
+        .arg(
+            Arg::with_name("keep-originals")
+                .long("keep-originals")
+                .takes_value(true)
+                .conflicts_with("trial")
+                .help("move replaced originals into this directory, so `decoreco restore` can undo the run"),
+        )
+        // or into the trash
+        .arg(
+            Arg::with_name("trash")
+                .long("trash")
+                .conflicts_with_all(&["trial", "keep-originals"])
+                .help("move replaced originals to the trash, so they can be restored from your file manager"),
+        )
+        // re-encode our own output
+        .arg(
+            Arg::with_name("include-tagged")
+                .long("include-tagged")
+                .help("also process files decoreco already encoded, which loses quality each time"),
+        )
+        // pick up where an interrupted run left off
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .conflicts_with_all(&["dry-run", "trial"])
+                .help("skip files an earlier run already replaced or skipped, using the journal"),
+        )
+        // what the first ctrl-c does
+        .arg(
+            Arg::with_name("on-interrupt")
+                .long("on-interrupt")
+                .takes_value(true)
+                .possible_values(&["wait", "kill"])
+                .default_value("wait")
+                .help("on ctrl-c, wait for running encodes to finish or kill them. a second ctrl-c always exits straight away"),
+        )
+        // json events instead of text, for other programs
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("print text and progress bars, or one json event per line"),
+        )
+        // write down what happened to every file
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .takes_value(true)
+                .conflicts_with_all(&["dry-run", "list"])
+                .help("write what happened to every file to this file"),
+        )
+        .arg(
+            Arg::with_name("report-format")
+                .long("report-format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json")
+                .help("the format of the --report file"),
+        )
+        // where to put encodes while they're running
+        .arg(
+            Arg::with_name("tmp-dir")
+                .long("tmp-dir")
+                .takes_value(true)
+                .help("directory to encode into. defaults to a hidden directory next to each file"),
         )
         .arg(
             Arg::with_name("images")
@@ -69,6 +203,10 @@ Please contribute a guide for your favorite shell! "
                 .short("D")
                 .long("depth")
                 .takes_value(true)
+                .validator(|e| match e.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("not a usize".to_string()),
+                })
                 .help("how many levels deep to search for media files"),
         )
         // and an option to print the files that would be processed and their sizes and exit
@@ -111,6 +249,62 @@ Please contribute a guide for your favorite shell! "
                 .default_value("h264")
                 .possible_values(&["h264", "hevc", "vp9", "vp8", "av1"]),
         )
+        // and quality options for the video codec
+        .arg(
+            Arg::with_name("crf")
+                .short("q")
+                .long("crf")
+                .takes_value(true)
+                .validator(|e| match e.parse::<u8>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("not a valid crf".to_string()),
+                })
+                .help("constant quality to encode video at, lower is better. default depends on the codec"),
+        )
+        .arg(
+            Arg::with_name("preset")
+                .short("p")
+                .long("preset")
+                .takes_value(true)
+                .help("encoder speed preset, e.g. slow for h264/hevc or a cpu-used number for vp8/vp9/av1"),
+        )
+        .arg(
+            Arg::with_name("tune")
+                .long("tune")
+                .takes_value(true)
+                .help("encoder tuning, e.g. film, animation or grain for h264/hevc"),
+        )
+        // or search for a crf that hits a quality target instead
+        .arg(
+            Arg::with_name("target-quality")
+                .long("target-quality")
+                .takes_value(true)
+                .conflicts_with("crf")
+                .validator(|e| match e.parse::<f64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("not a number".to_string()),
+                })
+                .help("search for the highest crf that still scores this, e.g. 0.98 for ssim or 95 for vmaf"),
+        )
+        .arg(
+            Arg::with_name("metric")
+                .long("metric")
+                .takes_value(true)
+                .default_value("ssim")
+                .possible_values(&["ssim", "psnr", "vmaf"])
+                .help("metric used by --target-quality. vmaf needs ffmpeg built with libvmaf"),
+        )
+        .arg(
+            Arg::with_name("quality-samples")
+                .long("quality-samples")
+                .takes_value(true)
+                .default_value("3")
+                .validator(|e| match e.parse::<usize>() {
+                    Ok(0) | Err(_) => Err("not a positive number".to_string()),
+                    Ok(_) => Ok(()),
+                })
+                .help("how many segments of each video --target-quality encodes to measure"),
+        )
         // add an audio codec option
         .arg(
             Arg::with_name("audio-codec")
@@ -121,6 +315,30 @@ Please contribute a guide for your favorite shell! "
                 .default_value("aac")
                 .possible_values(&["aac", "opus", "vorbis", "mp3"]),
         )
+        // and bitrate and channel options for the audio codec
+        .arg(
+            Arg::with_name("audio-bitrate")
+                .short("b")
+                .long("audio-bitrate")
+                .takes_value(true)
+                .validator(|e| crate::codec::AudioBitrate::parse(&e).map(|_| ()))
+                .help("audio bitrate, e.g. 128k for the whole stream or 64k/ch per channel"),
+        )
+        .arg(
+            Arg::with_name("downmix")
+                .long("downmix")
+                .help("downmix surround audio to stereo"),
+        )
+        .arg(
+            Arg::with_name("max-sample-rate")
+                .long("max-sample-rate")
+                .takes_value(true)
+                .validator(|e| match e.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("not a valid sample rate".to_string()),
+                })
+                .help("resample audio above this rate in Hz down to it, e.g. 48000"),
+        )
         // add an image codec option
         .arg(
             Arg::with_name("image-codec")
@@ -131,21 +349,43 @@ Please contribute a guide for your favorite shell! "
                 .default_value("jxl")
                 .possible_values(&["jxl"]),
         )
-               .arg(
+        // how many files to do at once, and how many threads each encoder gets
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .validator(positive)
+                .help("number of files to encode at once. defaults to one per core for images, and one per 8 cores for videos"),
+        )
+        .arg(
+            Arg::with_name("encoder-threads")
+                .long("encoder-threads")
+                .takes_value(true)
+                .validator(positive)
+                .help("number of threads each encoder gets. defaults to sharing the cores between jobs"),
+        )
+        // the old name for --jobs, where 0 meant pick automatically
+        .arg(
             Arg::with_name("threads")
                 .short("t")
                 .long("threads")
                 .takes_value(true)
-                .validator(|e| {
-                    let res: Result<usize, _> = e.parse();
-                    match res {
-                        Ok(_) => Ok(()),
-                        Err(_) => Err("not a usize".to_string()),
-                    }
-
+                .validator(|e| match e.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("must be a whole number".to_string()),
                 })
-                .help("number of threads to use. default is max for images, and one for videos")
-                .default_value("0"))
+                .conflicts_with("jobs")
+                .hidden(true),
+        )
+}
+
+/// Checks that an argument is a whole number above zero.
+fn positive(e: String) -> Result<(), String> {
+    match e.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("must be a whole number above 0".to_string()),
+    }
 }
 
 pub fn man() {
@@ -157,7 +397,88 @@ pub fn man() {
             Flag::new()
                 .short("d")
                 .long("dry-run")
-                .help("don't actually do anything"),
+                .help("print what would be done to each file, including the encoder command, without encoding anything"),
+        )
+        // and a trial flag
+        .flag(
+            Flag::new()
+                .long("trial")
+                .help("encode every file and report the savings, but keep the originals. also available as --no-replace"),
+        )
+        // and a force flag
+        .flag(
+            Flag::new()
+                .short("f")
+                .long("force")
+                .help("re-encode files even if ffprobe says they're already in the target codecs"),
+        )
+        // and a flag to skip the decode check
+        .flag(
+            Flag::new()
+                .long("no-decode-check")
+                .help("don't decode each encode all the way through before replacing the original. stream counts and durations are still checked, and images are still checked for empty output. decoding images needs djxl, which comes with cjxl"),
+        )
+        // and a flag to not preserve metadata
+        .flag(
+            Flag::new()
+                .long("no-preserve")
+                .help("by default replaced files keep the original's access and modification times, permissions, owner (where allowed) and user xattrs. this gives them fresh ones instead"),
+        )
+        // and a backup option
+        .option(
+            Opt::new("keep-originals")
+                .long("keep-originals")
+                .help("move each original into this directory instead of overwriting or deleting it. they're kept in a tree mirroring their absolute paths, listed in a decoreco-manifest.jsonl at the top. see BACKUPS for more info"),
+        )
+        // and a trash flag
+        .flag(
+            Flag::new()
+                .long("trash")
+                .help("move each original to the trash instead of overwriting or deleting it, following the freedesktop.org trash spec. files on the same filesystem as your home go to $XDG_DATA_HOME/Trash, others to .Trash/$UID or .Trash-$UID at the top of their mount"),
+        )
+        // and a flag to include tagged files
+        .flag(
+            Flag::new()
+                .long("include-tagged")
+                .help("also process files decoreco made. every file decoreco replaces gets a user.decoreco xattr, and videos also get a DECORECO=codec,crf,version container tag, and files with either are skipped by default so repeated runs don't lose quality each time"),
+        )
+        // and a resume flag
+        .flag(
+            Flag::new()
+                .long("resume")
+                .help("skip files an earlier run already replaced, found to get larger, or skipped, as long as they haven't changed since. files that failed are tried again. every run that replaces files records what happened to each one in $XDG_STATE_HOME/decoreco/journal.jsonl (~/.local/state by default), keyed by path, size and modification time. the summary also shows how much has been saved over every run"),
+        )
+        // and an interrupt option
+        .option(
+            Opt::new("on-interrupt")
+                .long("on-interrupt")
+                .help("what the first ctrl-c does to files that are already encoding: wait lets them finish and replace their originals, kill stops them and throws their partial output away. either way no new files are started and the summary is printed. a second ctrl-c kills everything, removes the temp dirs and exits straight away. SIGTERM and SIGHUP count as ctrl-c too")
+                .default_value("wait"),
+        )
+        // and an output option
+        .option(
+            Opt::new("output")
+                .long("output")
+                .help("text prints coloured text and progress bars for people. json replaces them with one json object per line on stdout, for wrappers and GUIs, each with an event field: run_started with the settings, file_discovered for each file that will be processed, job_started when a worker picks one up, progress while ffmpeg encodes it, job_finished or job_failed with the same fields as a --report entry, warning for anything else that goes wrong, and run_summary at the end. can't be used with --list or --dry-run")
+                .default_value("text"),
+        )
+        // and the report options
+        .option(
+            Opt::new("report")
+                .long("report")
+                .help("when the run is over, write what happened to every file to this file, including ones that were skipped, got larger or failed. each file gets its path, action (replaced, encoded, larger, skipped, tagged, resumed, rejected, failed or interrupted), old and new size in bytes, the codecs ffprobe found, duration and encode time in seconds, the encoder's exit status and the error, with anything unknown left as null"),
+        )
+        .option(
+            Opt::new("report-format")
+                .long("report-format")
+                .help("json for an array of objects, or csv with a header row and the codecs separated by spaces")
+                .default_value("json"),
+        )
+        // and a temp dir option
+        .option(
+            Opt::new("tmp-dir")
+                .long("tmp-dir")
+                .help("encode into this directory instead of a hidden .decoreco-tmp directory next to each file. the default keeps encodes on the same filesystem as the originals, so replacing them is a cheap rename and a small tmpfs doesn't fill up. files are only started once there's as much free space as the original's size, and wait for running encodes to finish otherwise"),
         )
         // and a depth option
         .option(
@@ -202,6 +523,42 @@ pub fn man() {
                 .help("set the video codec to use. see CODECS for more info")
                 .default_value("h264"),
         )
+        // and the video quality options
+        .option(
+            Opt::new("crf")
+                .short("q")
+                .long("crf")
+                .help("set the constant quality to encode video at, lower is better. see QUALITY for the defaults"),
+        )
+        .option(
+            Opt::new("preset")
+                .short("p")
+                .long("preset")
+                .help("set the encoder speed preset. see QUALITY for more info"),
+        )
+        .option(
+            Opt::new("tune")
+                .long("tune")
+                .help("set the encoder tuning, e.g. film, animation or grain for h264 and hevc"),
+        )
+        // and the quality search options
+        .option(
+            Opt::new("target-quality")
+                .long("target-quality")
+                .help("instead of a fixed crf, search each video for the highest crf that still reaches this score. see QUALITY for more info"),
+        )
+        .option(
+            Opt::new("metric")
+                .long("metric")
+                .help("the metric --target-quality is measured in: ssim, psnr or vmaf")
+                .default_value("ssim"),
+        )
+        .option(
+            Opt::new("quality-samples")
+                .long("quality-samples")
+                .help("how many segments of each video to encode while searching")
+                .default_value("3"),
+        )
         // and an audio codec option
         .option(
             Opt::new("audio-codec")
@@ -210,6 +567,40 @@ pub fn man() {
                 .help("set the audio codec to use. see CODECS for more info")
                 .default_value("aac"),
         )
+        // and the audio bitrate and channel options
+        .option(
+            Opt::new("audio-bitrate")
+                .short("b")
+                .long("audio-bitrate")
+                .help("set the audio bitrate, either for the whole stream (128k) or per channel (64k/ch). streams already in the target codec are only re-encoded if they're noticeably above it"),
+        )
+        .flag(
+            Flag::new()
+                .long("downmix")
+                .help("downmix audio with more than two channels to stereo"),
+        )
+        .option(
+            Opt::new("max-sample-rate")
+                .long("max-sample-rate")
+                .help("resample audio above this rate in Hz down to it"),
+        )
+        // and the concurrency options
+        .option(
+            Opt::new("jobs")
+                .short("j")
+                .long("jobs")
+                .help("how many files to encode at once. by default images get one job per core, since cjxl doesn't gain much from more threads, and videos get one job per 8 cores, since video encoders do. -t and --threads are the old names for this"),
+        )
+        .option(
+            Opt::new("encoder-threads")
+                .long("encoder-threads")
+                .help("how many threads each encoder gets, passed to ffmpeg as -threads and to cjxl as --num_threads. by default the cores are shared out between jobs, with one thread each for images"),
+        )
+        // and the environment
+        .env(
+            Env::new("NO_COLOR")
+                .help("if set, nothing is coloured. colours are also left out when stdout isn't a terminal, and the progress bars when stderr isn't, in which case what happened to each file is printed on a line of its own instead"),
+        )
         .example(
             Example::new()
                 .text("re-encode all video files in your downloads folder to h264 and aac")
@@ -227,14 +618,54 @@ pub fn man() {
         )
         .example(
             Example::new()
-                .text("perform a dry run of converting your movies folder to avi")
-                .command("decoreco -d -v avi ~/Movies"),
+                .text("show what converting your movies folder to vp9 would do")
+                .command("decoreco -d -v vp9 ~/Movies"),
+        )
+        .example(
+            Example::new()
+                .text("see how much converting your movies folder to hevc would save, without replacing anything")
+                .command("decoreco --trial -v hevc ~/Movies"),
         )
                 .custom(
             Section::new("codecs")
                 .paragraph("the following codecs are supported in order of general size while retaining quality, smallest to largest:")
                 .paragraph("(video) hevc, vp9, [h264], , vp8").paragraph("(audio) [aac], opus, vorbis, mp3")
                 .paragraph("HEVC (also known as H.265) isn't supported by many web browsers or operating systems at the moment, and as such some videos might not play after you re-encode them. This codec should only be used if you don't plan on sharing the files over the internet without transcoding them (like using a media server such as plex or emby), or unless you're confident that your software and hardware can play it.").paragraph("Encoding HEVC also takes quite a bit longer thn h264, due to the higher compression ratio.")
+        )
+        .example(
+            Example::new()
+                .text("convert your movies folder to hevc, keeping the originals so it can be undone")
+                .command("decoreco -v hevc --keep-originals ~/decoreco-backup ~/Movies"),
+        )
+        .custom(
+            Section::new("quality")
+                .paragraph("video is encoded in constant quality mode. if --crf or --preset aren't given, these defaults are used:")
+                .paragraph("h264 (libx264): crf 23, preset medium")
+                .paragraph("hevc (libx265): crf 28, preset medium")
+                .paragraph("vp9 (libvpx-vp9): crf 31, cpu-used 2, with -b:v 0 for constant quality")
+                .paragraph("vp8 (libvpx): crf 10, cpu-used 2, with a 1M bitrate ceiling")
+                .paragraph("av1 (libaom-av1): crf 30, cpu-used 4, with -b:v 0 for constant quality")
+                .paragraph("for vp8, vp9 and av1 the preset is passed as -cpu-used, where higher is faster. --tune maps to -tune-content for vp9.")
+                .paragraph("with --target-quality, a few 4 second segments of each video are encoded at different crfs and compared against the original, and the highest crf that still scores at least the target is used for the whole file. ssim scores go from 0 to 1, psnr is in dB and vmaf goes from 0 to 100. searching takes several extra encodes of each segment, but they're short.")
+        )
+        .custom(
+            Section::new("backups")
+                .paragraph("with --keep-originals, originals are hard linked into the backup directory before they're replaced, or copied if it's on another filesystem, and only listed in the manifest once the replacement is in place.")
+                .paragraph("decoreco restore <dir> [files...] moves them back over their replacements, deleting the jxl for images, and takes them out of the manifest. if a file was replaced more than once, the oldest version wins.")
+                .paragraph("decoreco purge-backups <dir> --older-than <age> deletes originals that were replaced at least that long ago, where the age is a number followed by s, m, h, d or w. the replacements become final.")
+        )
+        .custom(
+            Section::new("logs")
+                .paragraph("when ffmpeg or cjxl fails, whether encoding, measuring quality for --target-quality or checking an encode decodes, decoreco looks through what it wrote to stderr for a few common problems (an unknown encoder, a codec the container can't hold, a corrupt input, running out of disk space or being denied permission) and prints the likely reason instead of everything it said. the summary groups failed files by reason, with a hint for what to do about each one.")
+                .paragraph("everything the encoder said is kept in $XDG_STATE_HOME/decoreco/logs/<when the run started>/<n>-<file name>.log, ~/.local/state by default.")
+        )
+        .custom(
+            Section::new("exit status")
+                .paragraph("0 if every file was replaced, skipped or found to get larger.")
+                .paragraph("1 if the run couldn't start, e.g. because of a bad argument, a path that doesn't exist or a --tmp-dir that can't be written to.")
+                .paragraph("2 if some files failed or had their encodes rejected, but not all of them.")
+                .paragraph("3 if every file that needed encoding failed or was rejected. files that were skipped don't count.")
+                .paragraph("130 if ctrl-c, SIGTERM or SIGHUP stopped the run, either once the running files finished or straight away on a second one. failures are listed in the summary along with the step they failed at: discovery, probe, encode, verify, commit or io.")
         );
     // save to a tempdir
     let tempdir = Builder::new()
@@ -250,7 +681,7 @@ pub fn man() {
                 if status.success() {
                     Ok(())
                 } else {
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, "man failed"))
+                    Err(std::io::Error::other("man failed"))
                 }
             })
         })
@@ -0,0 +1,405 @@
+use crate::probe::{Stream, StreamKind};
+use std::fmt;
+
+/// How a video stream gets encoded: which ffmpeg encoder, and how hard it tries.
+#[derive(Debug, Clone)]
+pub struct VideoSettings {
+    /// The codec name as given on the command line and reported by ffprobe, e.g. `hevc`.
+    pub codec: String,
+    /// The ffmpeg encoder that produces `codec`, e.g. `libx265`.
+    pub encoder: &'static str,
+    pub crf: u8,
+    pub preset: Option<String>,
+    pub tune: Option<String>,
+    /// How many threads the encoder gets.
+    pub threads: usize,
+}
+
+/// Per-codec defaults, picked to be close to each encoder's own "good enough" point.
+struct VideoCodec {
+    encoder: &'static str,
+    default_crf: u8,
+    crf_range: (u8, u8),
+    default_preset: Option<&'static str>,
+    /// libvpx and libaom don't have presets, their speed knob is `-cpu-used`.
+    preset_flag: &'static str,
+    /// The tuning option, which isn't called `-tune` everywhere either.
+    tune_flag: &'static str,
+    /// Some encoders only do constant quality when a bitrate is also given.
+    bitrate: Option<&'static str>,
+}
+
+fn video_codec(codec: &str) -> VideoCodec {
+    match codec {
+        "h264" => VideoCodec {
+            encoder: "libx264",
+            default_crf: 23,
+            crf_range: (0, 51),
+            default_preset: Some("medium"),
+            preset_flag: "-preset",
+            tune_flag: "-tune",
+            bitrate: None,
+        },
+        "hevc" => VideoCodec {
+            encoder: "libx265",
+            default_crf: 28,
+            crf_range: (0, 51),
+            default_preset: Some("medium"),
+            preset_flag: "-preset",
+            tune_flag: "-tune",
+            bitrate: None,
+        },
+        "vp9" => VideoCodec {
+            encoder: "libvpx-vp9",
+            default_crf: 31,
+            crf_range: (0, 63),
+            default_preset: Some("2"),
+            preset_flag: "-cpu-used",
+            tune_flag: "-tune-content",
+            // -b:v 0 is what turns on pure constant quality mode
+            bitrate: Some("0"),
+        },
+        "vp8" => VideoCodec {
+            encoder: "libvpx",
+            default_crf: 10,
+            crf_range: (4, 63),
+            default_preset: Some("2"),
+            preset_flag: "-cpu-used",
+            tune_flag: "-tune",
+            // vp8 treats the bitrate as a ceiling in crf mode, and 0 isn't allowed
+            bitrate: Some("1M"),
+        },
+        "av1" => VideoCodec {
+            encoder: "libaom-av1",
+            default_crf: 30,
+            crf_range: (0, 63),
+            // libaom's own default is painfully slow
+            default_preset: Some("4"),
+            preset_flag: "-cpu-used",
+            tune_flag: "-tune",
+            bitrate: Some("0"),
+        },
+        _ => unreachable!(),
+    }
+}
+
+impl VideoSettings {
+    /// Reads the video codec and quality options from the command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `matches` - The `ArgMatches` struct from clap.
+    /// * `threads` - How many threads the encoder gets.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(VideoSettings)` with the codec's defaults filled in for anything not given.
+    /// * `Err(String)` if the crf is out of range for the chosen codec.
+    pub fn from_matches(matches: &clap::ArgMatches<'_>, threads: usize) -> Result<Self, String> {
+        let codec = matches.value_of("video-codec").expect("no video codec");
+        let defaults = video_codec(codec);
+        let crf = match matches.value_of("crf") {
+            Some(crf) => crf
+                .parse()
+                .map_err(|_| format!("{crf} is not a valid crf"))?,
+            None => defaults.default_crf,
+        };
+        let (min, max) = defaults.crf_range;
+        if crf < min || crf > max {
+            return Err(format!(
+                "crf for {codec} must be between {min} and {max}, got {crf}"
+            ));
+        }
+        Ok(Self {
+            codec: codec.to_string(),
+            encoder: defaults.encoder,
+            crf,
+            preset: matches
+                .value_of("preset")
+                .or(defaults.default_preset)
+                .map(ToString::to_string),
+            tune: matches.value_of("tune").map(ToString::to_string),
+            threads,
+        })
+    }
+
+    /// The lowest and highest crf the encoder accepts.
+    pub fn crf_range(&self) -> (u8, u8) {
+        video_codec(&self.codec).crf_range
+    }
+
+    /// Checks whether a stream has to go through the video encoder.
+    ///
+    /// Cover art is left alone, it's not worth running through a video encoder.
+    pub fn needs_encode(&self, stream: &Stream) -> bool {
+        stream.kind == StreamKind::Video && !stream.attached_pic && stream.codec != self.codec
+    }
+
+    /// Builds the ffmpeg arguments that encode one output stream with these settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The index of the output stream.
+    pub fn args(&self, stream: usize) -> Vec<String> {
+        let defaults = video_codec(&self.codec);
+        let mut args = vec![
+            format!("-c:{stream}"),
+            self.encoder.to_string(),
+            format!("-crf:{stream}"),
+            self.crf.to_string(),
+        ];
+        if let Some(bitrate) = defaults.bitrate {
+            args.push(format!("-b:{stream}"));
+            args.push(bitrate.to_string());
+        }
+        if let Some(preset) = &self.preset {
+            args.push(format!("{}:{stream}", defaults.preset_flag));
+            args.push(preset.clone());
+        }
+        if let Some(tune) = &self.tune {
+            args.push(format!("{}:{stream}", defaults.tune_flag));
+            args.push(tune.clone());
+        }
+        args
+    }
+}
+
+impl fmt::Display for VideoSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}), crf {}", self.codec, self.encoder, self.crf)?;
+        let defaults = video_codec(&self.codec);
+        if let Some(preset) = &self.preset {
+            write!(f, ", {} {preset}", &defaults.preset_flag[1..])?;
+        }
+        if let Some(tune) = &self.tune {
+            write!(f, ", {} {tune}", &defaults.tune_flag[1..])?;
+        }
+        Ok(())
+    }
+}
+
+/// How much bitrate to give an audio stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioBitrate {
+    /// A fixed rate for the whole stream, in bits per second.
+    Total(u64),
+    /// A rate for each channel, so 5.1 gets more than stereo, in bits per second.
+    PerChannel(u64),
+}
+
+impl AudioBitrate {
+    /// Parses a bitrate like `128k`, `1.5M` or `64k/ch`.
+    ///
+    /// # Arguments
+    ///
+    /// * `s` - The bitrate as given on the command line.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AudioBitrate)` if the bitrate is valid.
+    /// * `Err(String)` if it isn't, with the error message.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (rate, per_channel) = match s.strip_suffix("/ch") {
+            Some(rate) => (rate, true),
+            None => (s, false),
+        };
+        let (number, multiplier) = match rate.chars().last() {
+            Some('k' | 'K') => (&rate[..rate.len() - 1], 1_000.0),
+            Some('m' | 'M') => (&rate[..rate.len() - 1], 1_000_000.0),
+            _ => (rate, 1.0),
+        };
+        let bits = number
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite() && *n > 0.0)
+            .ok_or_else(|| format!("{s} is not a valid bitrate, try e.g. 128k or 64k/ch"))?;
+        let bits = (bits * multiplier) as u64;
+        Ok(if per_channel {
+            Self::PerChannel(bits)
+        } else {
+            Self::Total(bits)
+        })
+    }
+
+    /// Works out the bitrate for a stream with the given number of channels.
+    pub fn for_channels(self, channels: u64) -> u64 {
+        match self {
+            Self::Total(bits) => bits,
+            Self::PerChannel(bits) => bits * channels.max(1),
+        }
+    }
+}
+
+impl fmt::Display for AudioBitrate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Total(bits) => write!(f, "{}k", bits / 1000),
+            Self::PerChannel(bits) => write!(f, "{}k/ch", bits / 1000),
+        }
+    }
+}
+
+/// How an audio stream gets encoded.
+#[derive(Debug, Clone)]
+pub struct AudioSettings {
+    /// The codec name as given on the command line and reported by ffprobe, e.g. `opus`.
+    pub codec: String,
+    /// The ffmpeg encoder that produces `codec`, e.g. `libopus`.
+    pub encoder: &'static str,
+    pub bitrate: Option<AudioBitrate>,
+    /// Whether to mix anything with more than two channels down to stereo.
+    pub downmix: bool,
+    pub max_sample_rate: Option<u64>,
+}
+
+impl AudioSettings {
+    /// Reads the audio codec, bitrate and channel options from the command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `matches` - The `ArgMatches` struct from clap.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(AudioSettings)` if the options are valid.
+    /// * `Err(String)` if the bitrate or sample rate can't be parsed.
+    pub fn from_matches(matches: &clap::ArgMatches<'_>) -> Result<Self, String> {
+        let codec = matches.value_of("audio-codec").expect("no audio codec");
+        Ok(Self {
+            codec: codec.to_string(),
+            // ffmpeg's built in opus and vorbis encoders are experimental, so use the libs
+            encoder: match codec {
+                "aac" => "aac",
+                "opus" => "libopus",
+                "vorbis" => "libvorbis",
+                "mp3" => "libmp3lame",
+                _ => unreachable!(),
+            },
+            bitrate: matches
+                .value_of("audio-bitrate")
+                .map(AudioBitrate::parse)
+                .transpose()?,
+            downmix: matches.is_present("downmix"),
+            max_sample_rate: matches
+                .value_of("max-sample-rate")
+                .map(|r| {
+                    r.parse()
+                        .map_err(|_| format!("{r} is not a valid sample rate"))
+                })
+                .transpose()?,
+        })
+    }
+
+    /// The number of channels a stream will have after encoding.
+    fn channels(&self, stream: &Stream) -> u64 {
+        let channels = stream.channels.unwrap_or(2);
+        if self.downmix {
+            channels.min(2)
+        } else {
+            channels
+        }
+    }
+
+    /// Checks whether a stream has to go through the audio encoder, either because it's
+    /// in another codec or because it's bigger than the options allow.
+    pub fn needs_encode(&self, stream: &Stream) -> bool {
+        if stream.kind != StreamKind::Audio {
+            return false;
+        }
+        let too_many_channels = self.downmix && stream.channels.is_some_and(|c| c > 2);
+        let too_high_rate = self
+            .max_sample_rate
+            .is_some_and(|max| stream.sample_rate.is_some_and(|r| r > max));
+        // leave a bit of slack, measured bitrates are never exactly the target
+        let too_big = self.bitrate.is_some_and(|b| {
+            let target = b.for_channels(self.channels(stream));
+            stream.bit_rate.is_some_and(|r| r > target + target / 10)
+        });
+        stream.codec != self.codec || too_many_channels || too_high_rate || too_big
+    }
+
+    /// Builds the ffmpeg arguments that encode one audio stream with these settings.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The input stream, used for its channel count and sample rate.
+    pub fn args(&self, input: &Stream) -> Vec<String> {
+        let stream = input.index;
+        let mut args = vec![format!("-c:{stream}"), self.encoder.to_string()];
+        if let Some(bitrate) = self.bitrate {
+            args.push(format!("-b:{stream}"));
+            args.push(bitrate.for_channels(self.channels(input)).to_string());
+        }
+        if self.downmix && input.channels.is_some_and(|c| c > 2) {
+            args.push(format!("-ac:{stream}"));
+            args.push("2".to_string());
+        }
+        if let Some(max) = self.max_sample_rate {
+            if input.sample_rate.is_some_and(|r| r > max) {
+                args.push(format!("-ar:{stream}"));
+                args.push(max.to_string());
+            }
+        }
+        args
+    }
+}
+
+impl fmt::Display for AudioSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.codec, self.encoder)?;
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", {bitrate}")?;
+        }
+        if self.downmix {
+            write!(f, ", downmixed to stereo")?;
+        }
+        if let Some(max) = self.max_sample_rate {
+            write!(f, ", at most {max} Hz")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_total_bitrates() {
+        assert_eq!(
+            AudioBitrate::parse("128k"),
+            Ok(AudioBitrate::Total(128_000))
+        );
+        assert_eq!(
+            AudioBitrate::parse("128K"),
+            Ok(AudioBitrate::Total(128_000))
+        );
+        assert_eq!(
+            AudioBitrate::parse("1.5M"),
+            Ok(AudioBitrate::Total(1_500_000))
+        );
+        assert_eq!(
+            AudioBitrate::parse("96000"),
+            Ok(AudioBitrate::Total(96_000))
+        );
+    }
+
+    #[test]
+    fn parses_per_channel_bitrates() {
+        let rate = AudioBitrate::parse("64k/ch").unwrap();
+        assert_eq!(rate, AudioBitrate::PerChannel(64_000));
+        assert_eq!(rate.for_channels(2), 128_000);
+        assert_eq!(rate.for_channels(6), 384_000);
+        // streams that don't say how many channels they have count as one
+        assert_eq!(rate.for_channels(0), 64_000);
+    }
+
+    #[test]
+    fn rejects_bad_bitrates() {
+        for bad in [
+            "", "k", "abc", "0k", "-64k", "12x", "64k/", "/ch", "infk", "NaNk",
+        ] {
+            assert!(AudioBitrate::parse(bad).is_err(), "{bad:?} parsed");
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::tag;
+use std::{
+    fmt,
+    fs::{self, File, FileTimes},
+    io,
+    path::Path,
+};
+use tempfile::Builder;
+
+/// How replacing a file went wrong, and whether it had already been replaced by then.
+#[derive(Debug)]
+pub enum Error {
+    /// Nothing changed, the target is still whatever it was before.
+    Untouched(io::Error),
+    /// The target holds the encoded file, but syncing its directory or tidying up after
+    /// failed, so the rename might not survive a crash.
+    Replaced(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Untouched(e) => write!(f, "{e}"),
+            Self::Replaced(e) => write!(f, "replaced, but {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Replaces a file with an encoded one, without ever leaving a half-written file behind.
+///
+/// The encoded file is synced to disk and renamed over the target, so the target is
+/// either the old file or the complete new one even if we're killed or the disk fills
+/// up halfway through. If it's on another filesystem, it's copied next to the target
+/// and synced first, since renames are only atomic within a filesystem.
+///
+/// # Arguments
+///
+/// * `encoded` - The path to the encoded file. It's moved, not copied.
+/// * `target` - The path to write to, which may or may not exist yet.
+/// * `original` - A file to copy timestamps, permissions, ownership and xattrs from,
+///   usually the one being replaced. If `None`, the encoder's permissions are kept.
+/// * `tag` - A tag to mark the file with, see `tag::mark`. It's set before the
+///   permissions are copied, since a read-only original would stop it being set after.
+///
+/// # Returns
+///
+/// * `Ok(())` if the target now holds the encoded file.
+/// * `Err(Error::Untouched)` if it went wrong before the target was replaced.
+/// * `Err(Error::Replaced)` if the target was replaced, but something after that failed.
+pub fn replace(
+    encoded: &Path,
+    target: &Path,
+    original: Option<&Path>,
+    tag: Option<&str>,
+) -> Result<(), Error> {
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let staged = stage(encoded, target, dir, original, tag).map_err(Error::Untouched)?;
+    // past here the target's been replaced, so it's too late to say it hasn't
+    if let Some(staged) = staged {
+        fs::remove_file(staged).map_err(Error::Replaced)?;
+    }
+    sync_dir(dir).map_err(Error::Replaced)
+}
+
+/// Does the part of `replace` that can fail without touching the target, ending with the
+/// rename over it.
+///
+/// # Returns
+///
+/// * `Ok(None)` if the encoded file was renamed into place.
+/// * `Ok(Some(encoded))` if it had to be copied, so it's still there to be removed.
+/// * `Err(io::Error)` if anything went wrong, in which case the target is untouched.
+fn stage<'a>(
+    encoded: &'a Path,
+    target: &Path,
+    dir: &Path,
+    original: Option<&Path>,
+    tag: Option<&str>,
+) -> io::Result<Option<&'a Path>> {
+    // encodes made on the same filesystem can be renamed straight into place. it's only
+    // opened for reading, since restoring a backup moves a read-only original back
+    let file = File::open(encoded)?;
+    match original {
+        Some(original) => copy_metadata(original, &file, encoded, tag)?,
+        None => mark(encoded, tag),
+    }
+    file.sync_all()?;
+    match fs::rename(encoded, target) {
+        Ok(()) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        Err(e) => return Err(e),
+    }
+
+    // otherwise it's staged in the target's directory first
+    let mut staged = Builder::new().prefix(".decoreco").tempfile_in(dir)?;
+    io::copy(&mut File::open(encoded)?, staged.as_file_mut())?;
+    if let Some(original) = original {
+        copy_metadata(original, staged.as_file(), staged.path(), tag)?;
+    } else {
+        mark(staged.path(), tag);
+        // temp files are private by default, so give it the permissions the encoder gave the output
+        fs::set_permissions(staged.path(), fs::metadata(encoded)?.permissions())?;
+    }
+    staged.as_file().sync_all()?;
+    staged.persist(target).map_err(|e| e.error)?;
+    Ok(Some(encoded))
+}
+
+/// Hard links a file to a new path, or copies it there if they're on different filesystems.
+/// Fails with `AlreadyExists` if there's already something at the new path.
+pub fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            let mut file = File::options().write(true).create_new(true).open(to)?;
+            let copied = io::copy(&mut File::open(from)?, &mut file)
+                .and_then(|_| copy_metadata(from, &file, to, None))
+                .and_then(|()| file.sync_all());
+            if copied.is_err() {
+                let _ = fs::remove_file(to);
+            }
+            copied
+        }
+        res => res,
+    }
+}
+
+/// Makes a file look like the one it's replacing, so sorting by date and the like still works.
+///
+/// Ownership and xattrs are copied on a best-effort basis, since only root can give files
+/// away and not every filesystem supports xattrs. Timestamps and permissions have to stick.
+///
+/// # Arguments
+///
+/// * `original` - The file to copy from.
+/// * `file` - The open file to copy to.
+/// * `path` - The path of `file`, for the things that can only be set by path.
+/// * `tag` - A tag to mark it with, which replaces any the original had.
+pub fn copy_metadata(
+    original: &Path,
+    file: &File,
+    path: &Path,
+    tag: Option<&str>,
+) -> io::Result<()> {
+    let metadata = fs::metadata(original)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let _ = std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+        if let Ok(names) = xattr::list(original) {
+            // only user attributes, the others belong to the system or need privileges
+            for name in names.filter(|n| n.to_string_lossy().starts_with("user.")) {
+                if let Ok(Some(value)) = xattr::get(original, &name) {
+                    let _ = xattr::set(path, &name, &value);
+                }
+            }
+        }
+    }
+    // after the xattrs are copied, and before the permissions can make it read-only
+    mark(path, tag);
+    // set after chown, which can clear the setuid and setgid bits
+    fs::set_permissions(path, metadata.permissions())?;
+    file.set_times(
+        FileTimes::new()
+            .set_accessed(metadata.accessed()?)
+            .set_modified(metadata.modified()?),
+    )
+}
+
+/// Marks a file with a tag, if there is one and the filesystem lets us.
+fn mark(path: &Path, tag: Option<&str>) {
+    if let Some(tag) = tag {
+        let _ = tag::mark(path, tag);
+    }
+}
+
+/// Flushes a directory's entries to disk, so a rename inside it survives a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, so there's nothing to do.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn says_when_the_target_was_already_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+        let target = dir.join("a.mp4");
+        let encoded = dir.join("encoded.mp4");
+        fs::write(&target, b"original").unwrap();
+        fs::write(&encoded, b"encoded").unwrap();
+        // files can be renamed in it, but it can't be opened to sync it, unless we're root
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o300)).unwrap();
+        let res = replace(&encoded, &target, None, None);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(matches!(res, Ok(()) | Err(Error::Replaced(_))), "{res:?}");
+        assert_eq!(fs::read(&target).unwrap(), b"encoded");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tags_replacements_of_read_only_originals() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("a.mp4");
+        let encoded = tmp.path().join("encoded.mp4");
+        fs::write(&target, b"original").unwrap();
+        fs::write(&encoded, b"encoded").unwrap();
+        // not every filesystem has xattrs, and then there's nothing to check
+        if xattr::set(&target, "user.test", b"").is_err() {
+            return;
+        }
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o444)).unwrap();
+        replace(&encoded, &target, Some(&target), Some("hevc,28,1.0.0")).unwrap();
+        assert_eq!(tag::read(&target).as_deref(), Some("hevc,28,1.0.0"));
+        let mode = fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+    }
+}
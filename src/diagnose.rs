@@ -0,0 +1,359 @@
+use crate::journal;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Things encoders say when they fail, what they mean and what to do about them, checked
+/// in order. The patterns are matched case-insensitively against each line of stderr.
+const PATTERNS: &[(&[&str], &str, &str)] = &[
+    (
+        &["no space left on device"],
+        "out of disk space",
+        "free up some space, or use --tmp-dir to encode somewhere with more room",
+    ),
+    (
+        &["permission denied"],
+        "permission denied",
+        "check the file can be read and its directory (or --tmp-dir) written to",
+    ),
+    (
+        &[
+            "unknown encoder",
+            "encoder not found",
+            "not found for output stream",
+        ],
+        "unknown encoder",
+        "this ffmpeg wasn't built with the encoder for the chosen codec. install one that was, or pick another codec with -v or -a",
+    ),
+    (
+        &[
+            "not currently supported in container",
+            "could not find tag for codec",
+            "codec not supported",
+            // webm only takes a few codecs, so h264 and aac are out
+            "are supported for webm",
+        ],
+        "codec not supported by the container",
+        "the file's container can't hold the chosen codec, e.g. h264 in webm or vp9 in avi. pick another codec with -v or -a",
+    ),
+    (
+        &[
+            "invalid data found when processing input",
+            "moov atom not found",
+            "error while decoding",
+            "corrupt",
+        ],
+        "invalid or corrupt input",
+        "the original is probably damaged or cut short. check it plays all the way through",
+    ),
+];
+
+/// Lines ffmpeg ends with whenever it fails, which say nothing about why.
+const TRAILERS: &[&str] = &["conversion failed!"];
+
+/// A failed ffmpeg or cjxl run, boiled down to what probably went wrong.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// The step it failed in, `encode` or `verify`.
+    pub step: &'static str,
+    /// A short name for what went wrong, which failures are grouped by in the summary.
+    pub reason: &'static str,
+    /// What to do about it.
+    pub hint: &'static str,
+    /// The line of stderr that gave it away, or the last useful one if nothing did.
+    pub detail: String,
+    /// Where everything the encoder said was saved, once it has been.
+    pub log: Option<PathBuf>,
+    doing: String,
+    file: PathBuf,
+    stderr: String,
+    message: String,
+}
+
+impl Failure {
+    /// Works out why an encoder failed from what it wrote to stderr.
+    ///
+    /// # Arguments
+    ///
+    /// * `step` - The step it failed in, `encode` or `verify`.
+    /// * `doing` - What it was doing to the file, for the message, e.g. `encode` or
+    ///   `measure the ssim of`.
+    /// * `file` - The path to the original file.
+    /// * `stderr` - Everything it wrote to stderr.
+    pub fn new(step: &'static str, doing: &str, file: &Path, stderr: String) -> Self {
+        let lines: Vec<&str> = stderr
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        let found = PATTERNS.iter().find_map(|(patterns, reason, hint)| {
+            lines
+                .iter()
+                .find(|l| {
+                    let l = l.to_ascii_lowercase();
+                    patterns.iter().any(|p| l.contains(p))
+                })
+                .map(|l| (*reason, *hint, l.to_string()))
+        });
+        let (reason, hint, detail) = found.unwrap_or_else(|| {
+            (
+                if step == "verify" {
+                    "output doesn't decode cleanly"
+                } else {
+                    "encoder failed"
+                },
+                "see the log for everything it said",
+                lines
+                    .iter()
+                    .rev()
+                    .find(|l| !TRAILERS.contains(&l.to_ascii_lowercase().as_str()))
+                    .map_or_else(String::new, ToString::to_string),
+            )
+        });
+        let mut failure = Self {
+            step,
+            reason,
+            hint,
+            detail,
+            log: None,
+            doing: doing.to_string(),
+            file: file.to_path_buf(),
+            stderr,
+            message: String::new(),
+        };
+        failure.describe();
+        failure
+    }
+
+    /// The whole message, e.g. `failed to encode a.mkv: unknown encoder (...)`.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Fills in the message from everything else.
+    fn describe(&mut self) {
+        self.message = format!(
+            "failed to {} {}: {} ({})",
+            self.doing,
+            self.file.display(),
+            self.reason,
+            self.detail
+        );
+        if let Some(log) = &self.log {
+            self.message += &format!(". full output in {}", log.display());
+        }
+    }
+}
+
+/// Where a run keeps the full output of encoders that failed, one file each, in
+/// `$XDG_STATE_HOME/decoreco/logs/<when the run started>`.
+pub struct Logs {
+    dir: Option<PathBuf>,
+}
+
+impl Logs {
+    /// Picks the directory for this run's logs. It's only made once something fails.
+    pub fn start() -> Self {
+        let started = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+        Self {
+            dir: journal::state_dir()
+                .ok()
+                .map(|dir| dir.join("logs").join(started)),
+        }
+    }
+
+    /// Saves everything an encoder said, and points the failure at it.
+    ///
+    /// If it can't be saved, the failure just doesn't get a log.
+    ///
+    /// # Arguments
+    ///
+    /// * `failure` - The failure to save the output of.
+    /// * `index` - The file's place in the run, so files with the same name don't clash.
+    pub fn keep(&self, failure: &mut Failure, index: usize) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let name = failure
+            .file
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+        let log = dir.join(format!("{index}-{name}.log"));
+        let written = fs::create_dir_all(dir)
+            .and_then(|()| fs::File::create(&log))
+            .and_then(|mut f| {
+                writeln!(f, "{}\n", failure.file.display())?;
+                f.write_all(failure.stderr.as_bytes())
+            });
+        if written.is_ok() {
+            failure.log = Some(log);
+            failure.describe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Diagnoses some encoder output for `a.mkv`.
+    fn encode(stderr: &str) -> Failure {
+        Failure::new("encode", "encode", Path::new("a.mkv"), stderr.to_string())
+    }
+
+    #[test]
+    fn finds_full_disks() {
+        let failure = encode(
+            "\
+frame= 2400 fps= 80 q=28.0 size=  102400kB time=00:01:40.00 bitrate=8388.6kbits/s speed=3.3x
+av_interleaved_write_frame(): No space left on device
+[matroska @ 0x55d5c8a0] Error writing trailer of out.mkv: No space left on device
+Conversion failed!
+",
+        );
+        assert_eq!(failure.reason, "out of disk space");
+        assert_eq!(
+            failure.detail,
+            "av_interleaved_write_frame(): No space left on device"
+        );
+    }
+
+    #[test]
+    fn finds_permission_errors() {
+        let failure = encode("/mnt/ro/.decoreco-tmpAbC12/0.mkv: Permission denied\n");
+        assert_eq!(failure.reason, "permission denied");
+    }
+
+    #[test]
+    fn finds_unknown_encoders() {
+        // ffmpeg 6 and later
+        let failure = encode(
+            "\
+Stream mapping:
+  Stream #0:0 -> #0:0 (h264 (native) -> av1 (libsvtav1))
+[vost#0:0 @ 0x6000011e4000] Unknown encoder 'libsvtav1'
+[vost#0:0 @ 0x6000011e4000] Error selecting an encoder
+Error opening output file out.mkv.
+Error opening output files: Encoder not found
+",
+        );
+        assert_eq!(failure.reason, "unknown encoder");
+        assert_eq!(
+            failure.detail,
+            "[vost#0:0 @ 0x6000011e4000] Unknown encoder 'libsvtav1'"
+        );
+        // ffmpeg 4 names the codec instead when there's no encoder for it at all
+        let failure = encode("Encoder (codec av1) not found for output stream #0:0\n");
+        assert_eq!(failure.reason, "unknown encoder");
+    }
+
+    #[test]
+    fn finds_codecs_the_container_cant_hold() {
+        let failure = encode(
+            "\
+[avi @ 0x5627d1e0] Could not find tag for codec vp9 in stream #0, codec not currently supported in container
+Could not write header for output file #0 (incorrect codec parameters ?): Invalid argument
+",
+        );
+        assert_eq!(failure.reason, "codec not supported by the container");
+    }
+
+    #[test]
+    fn finds_codecs_webm_cant_hold() {
+        let failure = Failure::new(
+            "encode",
+            "encode",
+            Path::new("a.webm"),
+            "\
+[webm @ 0x55f1c0a4e2c0] Only VP8 or VP9 or AV1 video and Vorbis or Opus audio and WebVTT subtitles are supported for WebM.
+[out#0/webm @ 0x55f1c0a4d980] Could not write header (incorrect codec parameters ?): Invalid argument
+Conversion failed!
+"
+            .to_string(),
+        );
+        assert_eq!(failure.reason, "codec not supported by the container");
+        assert!(failure.detail.contains("supported for WebM"));
+    }
+
+    #[test]
+    fn finds_corrupt_input() {
+        let failure = encode(
+            "\
+[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55a8e2c0] moov atom not found
+a.mkv: Invalid data found when processing input
+",
+        );
+        assert_eq!(failure.reason, "invalid or corrupt input");
+        assert_eq!(
+            failure.detail,
+            "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55a8e2c0] moov atom not found"
+        );
+    }
+
+    #[test]
+    fn prefers_earlier_patterns() {
+        // a full disk can make ffmpeg complain about everything else too
+        let failure = encode(
+            "\
+[h264 @ 0x1] error while decoding MB 3 4
+av_interleaved_write_frame(): No space left on device
+",
+        );
+        assert_eq!(failure.reason, "out of disk space");
+    }
+
+    #[test]
+    fn falls_back_to_the_last_line() {
+        let failure = encode(
+            "\
+JPEG XL encoder v0.10.2 [AVX2,SSE4,SSE2]
+Getting pixel data failed.
+
+",
+        );
+        assert_eq!(failure.reason, "encoder failed");
+        assert_eq!(failure.detail, "Getting pixel data failed.");
+        // ffmpeg's sign-off doesn't say anything
+        let failure = encode(
+            "\
+[libx264 @ 0x5581] broken ffmpeg default settings detected
+Error initializing output stream 0:0 -- Error while opening encoder for output stream #0:0
+Conversion failed!
+",
+        );
+        assert_eq!(
+            failure.detail,
+            "Error initializing output stream 0:0 -- Error while opening encoder for output stream #0:0"
+        );
+        let failure = encode("Conversion failed!\n");
+        assert_eq!(failure.detail, "");
+        let failure = encode("");
+        assert_eq!(failure.reason, "encoder failed");
+        assert_eq!(failure.detail, "");
+        // the decode check gets a fallback of its own
+        let failure = Failure::new(
+            "verify",
+            "decode the encode of",
+            Path::new("a.mkv"),
+            "something odd\n".to_string(),
+        );
+        assert_eq!(failure.reason, "output doesn't decode cleanly");
+    }
+
+    #[test]
+    fn describes_the_failure() {
+        let mut failure = encode("Unknown encoder 'libx265'\n");
+        assert_eq!(
+            failure.message(),
+            "failed to encode a.mkv: unknown encoder (Unknown encoder 'libx265')"
+        );
+        failure.log = Some(PathBuf::from("/logs/0-a.mkv.log"));
+        failure.describe();
+        assert_eq!(
+            failure.message(),
+            "failed to encode a.mkv: unknown encoder (Unknown encoder 'libx265'). full output in /logs/0-a.mkv.log"
+        );
+    }
+}
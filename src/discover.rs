@@ -0,0 +1,101 @@
+use crate::events::Sink;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// File extensions that are picked up when searching for videos.
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "mkv", "webm", "mov", "avi"];
+
+/// File extensions that are picked up when searching for images.
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "avif", "heic"];
+
+/// The start of the name of the temp dirs encodes are made in next to their originals.
+/// A run that was killed can leave them behind, full of half-finished encodes.
+pub const TMP_PREFIX: &str = ".decoreco-tmp";
+
+/// Recursively searches a path for regular files with one of the given extensions.
+///
+/// # Arguments
+///
+/// * `root` - The file or directory to search.
+/// * `depth` - How many levels below `root` to descend, like `find -maxdepth`. `None` means no limit.
+/// * `extensions` - The extensions to match, compared case-insensitively.
+/// * `skip` - Directories not to search, like the `--keep-originals` one, as canonical paths.
+/// * `sink` - Where to warn about subdirectories that can't be read.
+///
+/// # Returns
+///
+/// * `Ok(Vec<PathBuf>)` with every matching file, in directory order.
+/// * `Err(io::Error)` if `root` itself can't be read. Unreadable subdirectories are
+///   reported and skipped instead, and so are the temp dirs of earlier runs and trashes.
+pub fn find_media(
+    root: &Path,
+    depth: Option<usize>,
+    extensions: &[&str],
+    skip: &[PathBuf],
+    sink: &Sink,
+) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    // symlinks aren't followed, same as find
+    let file_type = fs::symlink_metadata(root)?.file_type();
+    if file_type.is_dir() {
+        walk(root, 1, depth, extensions, skip, sink, &mut files)?;
+    } else if file_type.is_file() && has_extension(root, extensions) {
+        files.push(root.to_path_buf());
+    }
+    Ok(files)
+}
+
+/// Reads a single directory, collecting matches and descending into subdirectories.
+fn walk(
+    dir: &Path,
+    level: usize,
+    depth: Option<usize>,
+    extensions: &[&str],
+    skip: &[PathBuf],
+    sink: &Sink,
+    files: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    if depth.is_some_and(|d| level > d) {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if skip_dir(&path, skip) {
+                continue;
+            }
+            if let Err(err) = walk(&path, level + 1, depth, extensions, skip, sink, files) {
+                sink.warn(&format!("failed to search '{}': {err}", path.display()));
+            }
+        } else if file_type.is_file() && has_extension(&path, extensions) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether a directory holds files that shouldn't be encoded: our own temp dirs,
+/// originals that were put in the trash, or any of the ones in `skip`.
+fn skip_dir(dir: &Path, skip: &[PathBuf]) -> bool {
+    let Some(name) = dir.file_name() else {
+        return false;
+    };
+    let n = name.to_string_lossy();
+    if n.starts_with(TMP_PREFIX) || n == ".Trash" || n.starts_with(".Trash-") {
+        return true;
+    }
+    // only resolve the path if the name matches, so most directories don't need it
+    skip.iter()
+        .any(|s| s.file_name() == Some(name) && fs::canonicalize(dir).is_ok_and(|dir| &dir == s))
+}
+
+/// Checks whether a path ends in one of the given extensions, ignoring case.
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| extensions.iter().any(|x| x.eq_ignore_ascii_case(e)))
+}
@@ -0,0 +1,234 @@
+use crate::{diagnose, report};
+use colored::Colorize;
+use std::fmt;
+
+/// The exit code when a run can't get started, e.g. because the files couldn't be found
+/// or the temp dir couldn't be made. clap uses it for bad arguments too.
+pub const SETUP: i32 = 1;
+/// The exit code when some files failed or were rejected, but not all of them.
+pub const SOME_FAILED: i32 = 2;
+/// The exit code when every file that needed encoding failed or was rejected.
+pub const ALL_FAILED: i32 = 3;
+/// The exit code when ctrl-c, SIGTERM or SIGHUP stopped the run, whether it waited for the
+/// running files to finish or not. It's what shells use for a process killed by SIGINT.
+pub const INTERRUPTED: i32 = 130;
+
+/// Something that went wrong with a file, sorted by which step it went wrong in.
+///
+/// Each one holds the whole message, which says what was being done, e.g.
+/// `failed to probe: ...`.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// Looking for files, or reading them once found.
+    Discovery(String),
+    /// Running ffprobe.
+    Probe(String),
+    /// Searching for a crf, or starting the encoder.
+    Encode(String),
+    /// ffmpeg, cjxl or djxl ran but failed while encoding or verifying, with what probably went
+    /// wrong.
+    Encoder(Box<diagnose::Failure>),
+    /// Checking the encode is complete before it replaces anything.
+    Verify(String),
+    /// Putting the encode in the original's place, and keeping or trashing the original.
+    Commit(String),
+    /// Anything else to do with the filesystem, like making temp dirs.
+    Io(String),
+}
+
+impl Error {
+    /// The step it went wrong in, as shown in the summary and the report.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Discovery(_) => "discovery",
+            Self::Probe(_) => "probe",
+            Self::Encode(_) => "encode",
+            Self::Encoder(failure) => failure.step,
+            Self::Verify(_) => "verify",
+            Self::Commit(_) => "commit",
+            Self::Io(_) => "io",
+        }
+    }
+
+    /// What went wrong.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Discovery(message)
+            | Self::Probe(message)
+            | Self::Encode(message)
+            | Self::Verify(message)
+            | Self::Commit(message)
+            | Self::Io(message) => message,
+            Self::Encoder(failure) => failure.message(),
+        }
+    }
+
+    /// What went wrong in a few words, which failures are grouped by in the summary.
+    /// It's the step, unless the encoder's output said more.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::Encoder(failure) => failure.reason,
+            _ => self.kind(),
+        }
+    }
+
+    /// What to do about it, if we know.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Encoder(failure) => Some(failure.hint),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Prints an error that stops the run before it starts, and exits with `SETUP`.
+///
+/// # Arguments
+///
+/// * `err` - What went wrong.
+pub fn setup(err: impl fmt::Display) -> ! {
+    // the same as clap's errors look
+    eprintln!("{} {err}", "error:".red().bold());
+    std::process::exit(SETUP)
+}
+
+/// Works out the exit code for a run from what happened to each file.
+///
+/// Files that were skipped or left for an earlier run don't count either way, so a run
+/// where the only file that needed encoding failed has failed completely. A run that was
+/// interrupted didn't finish, so that's what it says, whatever happened to the files.
+///
+/// # Arguments
+///
+/// * `entries` - What happened to every file.
+/// * `interrupted` - Whether ctrl-c, SIGTERM or SIGHUP stopped the run early.
+///
+/// # Returns
+///
+/// * `INTERRUPTED` if the run was interrupted.
+/// * Otherwise `0` if nothing failed, `SOME_FAILED` if some files did, or `ALL_FAILED` if
+///   every file that was tried did.
+pub fn exit_code(entries: &[report::Entry], interrupted: bool) -> i32 {
+    use report::Action;
+    if interrupted {
+        return INTERRUPTED;
+    }
+    let failed = entries
+        .iter()
+        .filter(|e| matches!(e.action, Action::Failed | Action::Rejected))
+        .count();
+    let tried = entries
+        .iter()
+        .filter(|e| {
+            !matches!(
+                e.action,
+                Action::Skipped | Action::Tagged | Action::Resumed | Action::Interrupted
+            )
+        })
+        .count();
+    if failed == 0 {
+        0
+    } else if failed == tried {
+        ALL_FAILED
+    } else {
+        SOME_FAILED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use report::{Action, Entry};
+    use std::path::Path;
+
+    /// An entry for a file that ended up with each action.
+    fn entries(actions: &[Action]) -> Vec<Entry> {
+        actions
+            .iter()
+            .map(|&a| Entry::with_action(Path::new("a.mkv"), a))
+            .collect()
+    }
+
+    #[test]
+    fn exits_cleanly_when_nothing_failed() {
+        assert_eq!(exit_code(&[], false), 0);
+        assert_eq!(
+            exit_code(
+                &entries(&[Action::Replaced, Action::Larger, Action::Encoded]),
+                false
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn exits_cleanly_when_everything_was_skipped() {
+        assert_eq!(
+            exit_code(
+                &entries(&[
+                    Action::Skipped,
+                    Action::Tagged,
+                    Action::Resumed,
+                    Action::Interrupted
+                ]),
+                false
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn says_when_it_was_interrupted() {
+        // even if the files that were running all finished
+        assert_eq!(exit_code(&entries(&[Action::Replaced]), true), INTERRUPTED);
+        assert_eq!(
+            exit_code(&entries(&[Action::Failed, Action::Interrupted]), true),
+            INTERRUPTED
+        );
+    }
+
+    #[test]
+    fn counts_some_failures() {
+        assert_eq!(
+            exit_code(&entries(&[Action::Replaced, Action::Failed]), false),
+            SOME_FAILED
+        );
+        assert_eq!(
+            exit_code(
+                &entries(&[Action::Larger, Action::Rejected, Action::Skipped]),
+                false
+            ),
+            SOME_FAILED
+        );
+    }
+
+    #[test]
+    fn counts_all_failures() {
+        assert_eq!(exit_code(&entries(&[Action::Failed]), false), ALL_FAILED);
+        assert_eq!(
+            exit_code(&entries(&[Action::Failed, Action::Rejected]), false),
+            ALL_FAILED
+        );
+        // skipped files don't stop it counting as a complete failure
+        assert_eq!(
+            exit_code(
+                &entries(&[
+                    Action::Skipped,
+                    Action::Failed,
+                    Action::Tagged,
+                    Action::Interrupted
+                ]),
+                false
+            ),
+            ALL_FAILED
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use crate::report;
+use colored::Colorize;
+use serde::Serialize;
+use std::{borrow::Cow, collections::BTreeMap, fmt::Display, io::Write, path::Path};
+
+/// Something that happened during a run, written as a line of json with `--output json`.
+///
+/// Every event is an object with an `event` field naming it, e.g.
+/// `{"event":"job_started","path":"a.mkv"}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The settings for the run, before any files are looked for.
+    RunStarted {
+        version: &'static str,
+        /// `replace`, or `trial` if the originals are being kept.
+        mode: &'static str,
+        images: bool,
+        jobs: usize,
+        encoder_threads: usize,
+        /// The encoder settings, as shown in the text summary. `None` for images.
+        video: Option<String>,
+        audio: Option<String>,
+    },
+    /// A file that's going to be processed, in the order they'll be started.
+    FileDiscovered { path: Cow<'a, str>, size: u64 },
+    /// A file has been picked up by a worker.
+    JobStarted { path: Cow<'a, str> },
+    /// How far through encoding a file is, roughly twice a second, from ffmpeg's `-progress`.
+    Progress {
+        path: Cow<'a, str>,
+        /// Milliseconds encoded so far, and the file's duration.
+        done: u64,
+        total: u64,
+        /// ffmpeg's speed, e.g. `1.5x`, if it's said yet.
+        speed: Option<String>,
+    },
+    /// A file is done with, whether it was replaced, skipped or anything else that isn't
+    /// a failure. It has every field of a `--report` entry. Files that are dropped before
+    /// the run starts, like empty or already tagged ones, get one without a `job_started`.
+    JobFinished(&'a report::Entry),
+    /// A file failed or its encode was rejected, with the error in the entry.
+    JobFailed(&'a report::Entry),
+    /// Something went wrong that isn't about one particular file.
+    Warning { message: &'a str },
+    /// The end of the run.
+    RunSummary {
+        /// How many files ended up with each action.
+        actions: BTreeMap<report::Action, usize>,
+        /// The total size of the replaced files before and after, and the difference.
+        old_size: u64,
+        new_size: u64,
+        saved: u64,
+        /// How long the run took in seconds.
+        elapsed: f64,
+        /// What decoreco is about to exit with, see EXIT STATUS in the man page.
+        exit_code: i32,
+    },
+}
+
+/// Where a run's output goes: coloured text for people, or json events for programs.
+pub struct Sink {
+    json: bool,
+}
+
+impl Sink {
+    /// Sets up the output.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - Whether to write json events instead of text.
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    /// Whether json events are being written, so there's no text or progress bars.
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    /// Writes an event as a line of json, if that's what's wanted.
+    pub fn emit(&self, event: &Event<'_>) {
+        if !self.json {
+            return;
+        }
+        let line = serde_json::to_string(event).expect("failed to serialize event");
+        // lock so lines from different workers can't interleave
+        let mut out = std::io::stdout().lock();
+        let _ = writeln!(out, "{line}").and_then(|()| out.flush());
+    }
+
+    /// Prints a line of text, if that's what's wanted.
+    pub fn say(&self, line: impl Display) {
+        if !self.json {
+            println!("{line}");
+        }
+    }
+
+    /// Prints a warning in yellow, or writes it as a `warning` event.
+    pub fn warn(&self, message: &str) {
+        if self.json {
+            self.emit(&Event::Warning { message });
+        } else {
+            println!("{}", message.yellow());
+        }
+    }
+
+    /// Writes how a file turned out, as `job_failed` if it failed or was rejected and
+    /// `job_finished` otherwise.
+    pub fn finished(&self, entry: &report::Entry) {
+        match entry.action {
+            report::Action::Failed | report::Action::Rejected => {
+                self.emit(&Event::JobFailed(entry));
+            }
+            _ => self.emit(&Event::JobFinished(entry)),
+        }
+    }
+}
+
+/// Turns a path into a string for an event, even if it isn't valid unicode.
+pub fn path(path: &Path) -> Cow<'_, str> {
+    path.to_string_lossy()
+}
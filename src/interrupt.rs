@@ -0,0 +1,168 @@
+use crate::error;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// How many times ctrl-c has been pressed.
+static PRESSES: AtomicUsize = AtomicUsize::new(0);
+/// Whether running encoders have been killed, so their failures aren't really failures.
+static KILLED: AtomicBool = AtomicBool::new(false);
+/// Every child process that's running right now.
+static CHILDREN: Mutex<Vec<Child>> = Mutex::new(Vec::new());
+/// Directories to remove if we have to exit straight away.
+static CLEANUP: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Sets up ctrl-c handling for a run.
+///
+/// The first ctrl-c stops new files from being started. Files that are already encoding
+/// are either left to finish or killed, depending on `kill`. A second ctrl-c kills
+/// everything, removes the temp dirs and exits immediately.
+///
+/// SIGTERM and SIGHUP are handled the same way, since encoders run in their own process
+/// group and wouldn't hear about them otherwise.
+///
+/// # Arguments
+///
+/// * `kill` - Whether to kill running encoders on the first ctrl-c instead of waiting.
+pub fn install(kill: bool) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        if PRESSES.fetch_add(1, Ordering::SeqCst) == 0 {
+            if kill {
+                say("\nstopping, press ctrl-c again to exit now.");
+                kill_all();
+            } else {
+                say("\nstopping once the running files finish, press ctrl-c again to exit now.");
+            }
+        } else {
+            kill_all();
+            for dir in CLEANUP.lock().expect("poisoned").iter() {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            say("\nexiting.");
+            std::process::exit(error::INTERRUPTED);
+        }
+    })
+}
+
+/// Prints to stderr without panicking if it's gone, like after SIGHUP closed the terminal.
+fn say(message: &str) {
+    let _ = writeln!(io::stderr(), "{message}");
+}
+
+/// Whether ctrl-c has been pressed, so no new files should be started.
+pub fn stopping() -> bool {
+    PRESSES.load(Ordering::SeqCst) != 0
+}
+
+/// Whether running encoders were killed, in which case whatever they were working on
+/// didn't fail, it was interrupted.
+pub fn killed() -> bool {
+    KILLED.load(Ordering::SeqCst)
+}
+
+/// Remembers a directory to remove if we have to exit without cleaning up properly.
+pub fn remove_on_exit(dir: PathBuf) {
+    CLEANUP.lock().expect("poisoned").push(dir);
+}
+
+/// Kills every running child process.
+fn kill_all() {
+    KILLED.store(true, Ordering::SeqCst);
+    for child in CHILDREN.lock().expect("poisoned").iter_mut() {
+        let _ = child.kill();
+    }
+}
+
+/// Runs a command to completion and collects its output, like `Command::output`, but
+/// in a way that lets ctrl-c kill it.
+///
+/// The child gets its own process group, so a ctrl-c in the terminal reaches us and
+/// not it, and we decide whether it's finished or killed. It also gets no stdin,
+/// since processes outside the foreground group can't read from the terminal.
+///
+/// # Arguments
+///
+/// * `cmd` - The command to run.
+pub fn output(cmd: &mut Command) -> io::Result<Output> {
+    output_with(cmd, |_| {})
+}
+
+/// Like `output`, but hands each line the command writes to stdout to `on_line` as soon
+/// as it's written, which is how ffmpeg's progress gets read.
+///
+/// # Arguments
+///
+/// * `cmd` - The command to run.
+/// * `on_line` - Called with each line of stdout, without the newline.
+pub fn output_with(cmd: &mut Command, on_line: impl FnMut(&str) + Send) -> io::Result<Output> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(cmd, 0);
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let id = child.id();
+    {
+        let mut children = CHILDREN.lock().expect("poisoned");
+        // it might have been started just after everything else was killed
+        if killed() {
+            let _ = child.kill();
+        }
+        children.push(child);
+    }
+
+    std::thread::scope(|s| {
+        // read both pipes as we go, otherwise a chatty child fills one up and blocks
+        let stdout = s.spawn(|| read_lines(stdout, on_line));
+        let stderr = s.spawn(|| read_lines(stderr, |_| {}));
+
+        // the handler needs the child to kill it, so it stays in the list while we poll it
+        let mut wait = Duration::from_millis(1);
+        let status = loop {
+            {
+                let mut children = CHILDREN.lock().expect("poisoned");
+                let pos = children
+                    .iter()
+                    .position(|c| c.id() == id)
+                    .expect("child went missing");
+                if children[pos].try_wait()?.is_some() {
+                    // it's already exited, so this returns straight away
+                    break children.swap_remove(pos).wait()?;
+                }
+            }
+            std::thread::sleep(wait);
+            wait = (wait * 2).min(Duration::from_millis(50));
+        };
+        Ok(Output {
+            status,
+            stdout: stdout.join().expect("reader panicked")?,
+            stderr: stderr.join().expect("reader panicked")?,
+        })
+    })
+}
+
+/// Reads a pipe to the end, passing on each line as it comes in.
+fn read_lines<R: Read>(pipe: Option<R>, mut on_line: impl FnMut(&str)) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let Some(pipe) = pipe else {
+        return Ok(buf);
+    };
+    let mut reader = BufReader::new(pipe);
+    loop {
+        let start = buf.len();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(buf);
+        }
+        on_line(String::from_utf8_lossy(&buf[start..]).trim_end());
+    }
+}
@@ -0,0 +1,200 @@
+use crate::report::Action;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// One line of the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The absolute path of the file as it is after the run.
+    path: PathBuf,
+    /// The size and modification time of the file after the run, so we can tell if it's
+    /// been changed since.
+    size: u64,
+    mtime: u64,
+    mtime_nsec: u32,
+    /// What happened to it, which is only ever replaced, larger, skipped, rejected or failed.
+    outcome: Action,
+    /// The size before and after encoding, if it got that far.
+    old_size: Option<u64>,
+    new_size: Option<u64>,
+    /// When it was recorded, in seconds since the unix epoch.
+    time: u64,
+}
+
+/// A record of what happened to every file decoreco has touched, kept across runs so an
+/// interrupted run can pick up where it left off.
+///
+/// It lives in `$XDG_STATE_HOME/decoreco/journal.jsonl`, one entry per line, and only
+/// ever gets appended to.
+pub struct Journal {
+    file: Mutex<File>,
+    entries: Mutex<HashMap<PathBuf, Vec<Entry>>>,
+}
+
+impl Journal {
+    /// Opens the journal, creating it if this is the first run.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Journal)` with every entry from earlier runs loaded.
+    /// * `Err(String)` if it couldn't be opened or read.
+    pub fn open() -> Result<Self, String> {
+        let dir = state_dir()?;
+        let path = dir.join("journal.jsonl");
+        let err = |e: io::Error| format!("failed to open {}: {e}", path.display());
+        fs::create_dir_all(&dir).map_err(err)?;
+        let file = File::options()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .map_err(err)?;
+
+        let mut contents = String::new();
+        (&file).read_to_string(&mut contents).map_err(err)?;
+        let mut entries: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+        for line in contents.lines() {
+            // a run that was killed mid-write can leave a partial last line, so skip it
+            if let Ok(entry) = serde_json::from_str::<Entry>(line) {
+                entries.entry(entry.path.clone()).or_default().push(entry);
+            }
+        }
+        // and make sure the next entry starts on a line of its own
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            (&file).write_all(b"\n").map_err(err)?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Checks whether a file was already dealt with by an earlier run, and hasn't changed since.
+    ///
+    /// Files that failed or were rejected don't count, so they get another go.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(Action)` with what happened last time, if it's been handled.
+    /// * `None` if it still needs to be processed.
+    pub fn handled(&self, file: &Path) -> Option<Action> {
+        let (path, size, mtime, mtime_nsec) = key(file).ok()?;
+        let entries = self.entries.lock().expect("poisoned");
+        entries
+            .get(&path)?
+            .iter()
+            .rev()
+            .find(|e| e.size == size && e.mtime == mtime && e.mtime_nsec == mtime_nsec)
+            .map(|e| e.outcome)
+            .filter(|o| !matches!(o, Action::Failed | Action::Rejected))
+    }
+
+    /// Adds an entry for a file, keyed by how it looks on disk right now.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file, after it's been replaced if it was.
+    /// * `outcome` - What happened to it.
+    /// * `sizes` - Its size before and after encoding, if it got that far.
+    pub fn record(
+        &self,
+        file: &Path,
+        outcome: Action,
+        sizes: Option<(u64, u64)>,
+    ) -> io::Result<()> {
+        let (path, size, mtime, mtime_nsec) = key(file)?;
+        let entry = Entry {
+            path,
+            size,
+            mtime,
+            mtime_nsec,
+            outcome,
+            old_size: sizes.map(|s| s.0),
+            new_size: sizes.map(|s| s.1),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        {
+            let mut file = self.file.lock().expect("poisoned");
+            file.write_all(&line)?;
+            file.sync_data()?;
+        }
+        self.entries
+            .lock()
+            .expect("poisoned")
+            .entry(entry.path.clone())
+            .or_default()
+            .push(entry);
+        Ok(())
+    }
+
+    /// Adds up how much space replacing files has saved, over every run.
+    ///
+    /// # Arguments
+    ///
+    /// * `under` - Only count files at or inside these paths.
+    ///
+    /// # Returns
+    ///
+    /// * The bytes saved, and how big the files were before they were replaced.
+    pub fn savings(&self, under: &[PathBuf]) -> (u64, u64) {
+        let under: Vec<PathBuf> = under
+            .iter()
+            .filter_map(|p| std::path::absolute(p).ok())
+            .collect();
+        let entries = self.entries.lock().expect("poisoned");
+        entries
+            .iter()
+            .filter(|(path, _)| under.iter().any(|u| path.starts_with(u)))
+            .flat_map(|(_, entries)| entries)
+            .filter(|e| e.outcome == Action::Replaced)
+            .filter_map(|e| Some((e.old_size?, e.new_size?)))
+            .fold((0, 0), |(saved, total), (old, new)| {
+                (saved + old.saturating_sub(new), total + old)
+            })
+    }
+}
+
+/// Where decoreco keeps things between runs, `$XDG_STATE_HOME/decoreco`.
+///
+/// # Returns
+///
+/// * `Ok(PathBuf)` with the directory, which might not exist yet.
+/// * `Err(String)` if there's nowhere to put it.
+pub fn state_dir() -> Result<PathBuf, String> {
+    Ok(std::env::var_os("XDG_STATE_HOME")
+        .filter(|d| Path::new(d).is_absolute())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/state")))
+        .ok_or_else(|| "neither XDG_STATE_HOME nor HOME are set".to_string())?
+        .join("decoreco"))
+}
+
+/// Works out what a file is keyed by in the journal: its absolute path, size and mtime.
+fn key(file: &Path) -> io::Result<(PathBuf, u64, u64, u32)> {
+    let metadata = fs::metadata(file)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok((
+        std::path::absolute(file)?,
+        metadata.len(),
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+    ))
+}
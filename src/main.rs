@@ -2,18 +2,34 @@
 
 use clap::Shell;
 use colored::Colorize;
-use indicatif::{ProgressBar, ProgressStyle};
 
 use prettytable::{row, Cell, Row, Table};
 use rayon::prelude::*;
 use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
     process::Command,
     sync::{Arc, Mutex},
-    time::Duration,
 };
-use tempfile::Builder;
 
+mod backup;
 mod cli;
+mod codec;
+mod commit;
+mod diagnose;
+mod discover;
+mod error;
+mod events;
+mod interrupt;
+mod journal;
+mod probe;
+mod progress;
+mod quality;
+mod report;
+mod tag;
+mod trash;
+mod verify;
+mod workspace;
 
 /// Truncates a given string to a maximum length and appends "..." to the end if truncated.
 ///
@@ -37,13 +53,50 @@ fn truncate(s: &str, max: usize) -> String {
     if max == 0 {
         return String::from("...");
     }
-    if s.len() > max {
-        format!("{}...", &s[..max])
+    // count chars rather than bytes so multi-byte names aren't cut mid-character
+    if s.chars().count() > max {
+        format!("{}...", s.chars().take(max).collect::<String>())
     } else {
         s.to_string()
     }
 }
 
+/// Truncates a string to fit in the terminal alongside some other columns.
+///
+/// Without a terminal, e.g. under cron or when piped into `tee`, there's nothing to fit
+/// so the string is left whole.
+///
+/// # Arguments
+///
+/// * `s` - A string slice to be truncated.
+/// * `reserved` - How many columns the rest of the line needs.
+fn fit(s: &str, reserved: usize) -> String {
+    match term_width() {
+        Some(width) => truncate(s, width.saturating_sub(reserved)),
+        None => s.to_string(),
+    }
+}
+
+/// The width of the terminal, if stdout is one.
+fn term_width() -> Option<usize> {
+    use std::io::IsTerminal;
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    term_size::dimensions().map(|(width, _)| width)
+}
+
+/// Prints a table, only in colour if `colored` thinks it should be.
+///
+/// prettytable checks for a terminal itself, but doesn't know about `NO_COLOR`.
+fn print_table(table: &Table) {
+    if colored::control::SHOULD_COLORIZE.should_colorize() {
+        table.printstd();
+    } else {
+        let _ = table.print(&mut std::io::stdout());
+    }
+}
+
 /// Converts bytes to a human-readable string.
 ///
 /// # Arguments
@@ -114,85 +167,99 @@ fn main() {
     if matches.is_present("manpage") {
         cli::man();
     }
+    if let Some(matches) = matches.subcommand_matches("restore") {
+        restore(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("purge-backups") {
+        purge_backups(matches);
+        return;
+    }
+    // text for people, or json events for programs
+    let sink = events::Sink::new(matches.value_of("output") == Some("json"));
+    if sink.json() && (matches.is_present("list") || matches.is_present("dry-run")) {
+        clap::Error::with_description(
+            "--output json can't be used with --list or --dry-run",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit()
+    }
+    let (jobs, encoder_threads) = concurrency(&matches);
     rayon::ThreadPoolBuilder::new()
-        .num_threads({
-            usize::from(
-                0 == matches
-                    .value_of("threads")
-                    .expect("no specified thread number?")
-                    .parse()
-                    .expect("not a usize?")
-                    && !matches.is_present("images"),
-            )
-        })
+        .num_threads(jobs)
         .build_global()
-        .expect("failed to set rayon thread number. is the thread count valid?");
+        .unwrap_or_else(|e| error::setup(format!("failed to start {jobs} jobs: {e}")));
+
+    // work out the video encoder settings up front so bad combinations fail early
+    let video = codec::VideoSettings::from_matches(&matches, encoder_threads).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    let audio = codec::AudioSettings::from_matches(&matches).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    let metric = quality::Metric::from_name(matches.value_of("metric").expect("no metric"));
+    let target_quality: Option<f64> = matches
+        .value_of("target-quality")
+        .map(|q| q.parse().expect("not a number?"));
+    if target_quality.is_some() && metric == quality::Metric::Vmaf && !quality::has_vmaf() {
+        clap::Error::with_description(
+            "this ffmpeg wasn't built with libvmaf, try --metric ssim instead",
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit()
+    }
+    let images = matches.is_present("images");
+    sink.emit(&events::Event::RunStarted {
+        version: env!("CARGO_PKG_VERSION"),
+        mode: if matches.is_present("trial") {
+            "trial"
+        } else {
+            "replace"
+        },
+        images,
+        jobs,
+        encoder_threads,
+        video: (!images).then(|| video.to_string()),
+        audio: (!images).then(|| audio.to_string()),
+    });
 
     // run the appropriate command
     // don't search for files if set is specified
-    let mut files: Vec<&str>;
-    // what???
-    let tmp: String;
+    let mut files: Vec<PathBuf>;
     if matches.is_present("set") {
         files = matches
-            .values_of("set")
+            .values_of_os("set")
             .expect("set arg was empty")
+            .map(PathBuf::from)
             .collect();
     } else if matches.is_present("path") {
-        let check_path = matches.value_of("path").expect("path arg was empty");
+        let check_path = Path::new(matches.value_of_os("path").expect("path arg was empty"));
         // searches for media files in the given path
-        println!("searching for media files in {check_path}");
-        let mut cmd = Command::new("find");
-        let cmd = cmd
-            .arg(check_path)
-            // set the depth to search
-            .args(if matches.is_present("depth") {
-                vec![
-                    "-maxdepth",
-                    matches.value_of("depth").expect("depth arg was empty"),
-                ]
-            } else {
-                vec![]
-            });
-        let list = if matches.is_present("images") {
-            cmd.arg("-type")
-                .arg("f")
-                .args({
-                    let types = "jpg jpeg avif heic".split(' ').collect::<Vec<&str>>();
-                    let mut args: Vec<String> = Vec::new();
-                    args.push("-name".to_owned());
-                    args.push("*.png".to_string());
-                    for t in types {
-                        args.push("-o".to_owned());
-                        args.push("-name".to_owned());
-                        args.push(format!("*.{t}"));
-                    }
-                    args
-                })
-                .output()
-                .unwrap_or_else(|e| panic!("failed to find files: {e}"))
+        sink.say(format!(
+            "searching for media files in {}",
+            check_path.display()
+        ));
+        let depth = matches
+            .value_of("depth")
+            .map(|d| d.parse().expect("not a usize?"));
+        let extensions = if matches.is_present("images") {
+            discover::IMAGE_EXTENSIONS
         } else {
             // only search for media files
-            cmd.arg("-type")
-                .arg("f")
-                .args({
-                    let types = "mkv webm mov avi".split(' ').collect::<Vec<&str>>();
-                    let mut args: Vec<String> = Vec::new();
-                    args.push("-name".to_owned());
-                    args.push("*.mp4".to_string());
-                    for t in types {
-                        args.push("-o".to_owned());
-                        args.push("-name".to_owned());
-                        args.push(format!("*.{t}"));
-                    }
-                    args
-                })
-                .output()
-                .unwrap_or_else(|e| panic!("failed to find files: {e}"))
+            discover::VIDEO_EXTENSIONS
         };
-        tmp = String::from_utf8(list.stdout).expect("failed to read file list, invalid utf-8?");
-        files = tmp.split('\n').collect();
-        files.retain(|x| !x.is_empty());
+        // originals kept from earlier runs shouldn't be encoded again, if they're in the path
+        let skip: Vec<PathBuf> = matches
+            .value_of_os("keep-originals")
+            .and_then(|dir| std::fs::canonicalize(dir).ok())
+            .into_iter()
+            .collect();
+        files =
+            discover::find_media(check_path, depth, extensions, &skip, &sink).unwrap_or_else(|e| {
+                error::setup(error::Error::Discovery(format!(
+                    "failed to find files: {e}"
+                )))
+            });
     } else {
         // errors out and prints help if no arguments are given
         app.clone().print_help().expect("idek");
@@ -201,207 +268,798 @@ fn main() {
 
     // exits if there are no files to process
     if files.is_empty() {
-        println!("no files found!");
+        sink.say("no files found!");
+        // wrappers still need to hear the run is over
+        sink.emit(&events::Event::RunSummary {
+            actions: std::collections::BTreeMap::new(),
+            old_size: 0,
+            new_size: 0,
+            saved: 0,
+            elapsed: 0.0,
+            exit_code: 0,
+        });
         return;
     }
-    // remove empty strings from the list of files
-    files.retain(|x| !x.trim().is_empty());
-
+    // files that are dropped before the run starts still go in the report
+    let mut unprocessed: Vec<report::Entry> = Vec::new();
+    // and the ones that failed outright, with the error, starting with any that can't be read
+    let mut failed: Vec<(PathBuf, error::Error)> = Vec::new();
     // remove empty files from the list of files
     files.retain(|x| match std::fs::metadata(x) {
-        Ok(e) => e.len() != 0,
+        Ok(e) if e.len() == 0 => {
+            let mut entry = report::Entry::with_action(x, report::Action::Skipped);
+            entry.old_size = Some(0);
+            unprocessed.push(entry);
+            false
+        }
+        Ok(_) => true,
         Err(err) => {
-            println!("{}", format!("failed to read file '{x}': {err}").red());
+            let err =
+                error::Error::Discovery(format!("failed to read file '{}': {err}", x.display()));
+            sink.say(err.message().red());
+            let mut entry = report::Entry::new(x);
+            entry.set_error(&err);
+            unprocessed.push(entry);
+            failed.push((x.clone(), err));
             false
         }
     });
-    println!(
+    // leave alone anything we made in an earlier run, re-encoding it only loses quality
+    if !matches.is_present("include-tagged") {
+        let before = files.len();
+        files.retain(|f| {
+            let untagged = tag::read(f).is_none();
+            if !untagged {
+                unprocessed.push(report::Entry::with_action(f, report::Action::Tagged));
+            }
+            untagged
+        });
+        let tagged = before - files.len();
+        if tagged != 0 {
+            sink.say(format!(
+                "skipping {tagged} file{} decoreco already encoded.",
+                if tagged == 1 { "" } else { "s" }
+            ));
+        }
+    }
+    // files that never make it to a worker still get an event each
+    for entry in &unprocessed {
+        sink.finished(entry);
+    }
+    sink.say(format!(
         "found {} file{}!",
         files.len(),
         if files.len() == 1 { "" } else { "s" }
-    );
+    ));
     // sort the files by size if the user requested it
     if matches.is_present("sort") {
-        files.sort_by(|a, b| {
-            let a = std::fs::metadata(a)
-                .expect("failed to read file info")
-                .len();
-            let b = std::fs::metadata(b)
-                .expect("failed to read file info")
-                .len();
-            a.cmp(&b)
-        });
+        // anything that's disappeared since sorts first, and fails when it's reached
+        files.sort_by_key(|f| std::fs::metadata(f).map_or(0, |m| m.len()));
         if matches.is_present("reverse") {
             files.reverse();
         }
     }
+    for file in &files {
+        sink.emit(&events::Event::FileDiscovered {
+            path: events::path(file),
+            size: std::fs::metadata(file).map_or(0, |m| m.len()),
+        });
+    }
 
     // if flag is set, print the files and their sizes in a table and exit
     if matches.is_present("list") {
         let mut table = Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-        table.set_titles(row!["file", "size"]);
+        // videos also get their codec, resolution, duration and bitrate from ffprobe
+        let videos = !matches.is_present("images");
+        if videos {
+            table.set_titles(row![
+                "file",
+                "size",
+                "codec",
+                "resolution",
+                "duration",
+                "bitrate"
+            ]);
+        } else {
+            table.set_titles(row!["file", "size"]);
+        }
+        let infos: Vec<Option<probe::MediaInfo>> = files
+            .par_iter()
+            .map(|file| {
+                if videos {
+                    probe::probe(file).ok()
+                } else {
+                    None
+                }
+            })
+            .collect();
 
-        for file in files {
-            let metadata = std::fs::metadata(file).expect("failed to read file info");
-            table.add_row(Row::new(vec![
-                // truncate to terminal width minus the size column
-                Cell::new(&truncate(
-                    file,
-                    term_size::dimensions()
-                        .expect("failed to get terminal dimensions")
-                        .0
-                        - 20,
-                )),
-                Cell::new(&humanize_bytes(metadata.len())),
-            ]));
+        for (file, info) in files.iter().zip(infos) {
+            let size = std::fs::metadata(file).map_or(0, |m| m.len());
+            let mut cells = vec![
+                // truncate to terminal width minus the other columns
+                Cell::new(&fit(&file.to_string_lossy(), if videos { 70 } else { 20 })),
+                Cell::new(&humanize_bytes(size)),
+            ];
+            if videos {
+                let info = info.as_ref();
+                cells.push(Cell::new(
+                    &info
+                        .map(|i| {
+                            i.video_streams()
+                                .map(|s| s.codec.as_str())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        })
+                        .unwrap_or_else(|| "?".to_string()),
+                ));
+                cells.push(Cell::new(
+                    &info
+                        .and_then(probe::MediaInfo::resolution)
+                        .map_or_else(|| "?".to_string(), |(w, h)| format!("{w}x{h}")),
+                ));
+                cells.push(Cell::new(&info.and_then(|i| i.duration).map_or_else(
+                    || "?".to_string(),
+                    |d| time_human((d * 1000.0) as u128),
+                )));
+                cells.push(Cell::new(
+                    &info
+                        .and_then(|i| i.bit_rate)
+                        .map_or_else(|| "?".to_string(), |b| format!("{} kb/s", b / 1000)),
+                ));
+            }
+            table.add_row(Row::new(cells));
         }
-        table.printstd();
+        print_table(&table);
         return;
     }
 
-    // keep a list of files that have been processed and their old and new sizes
-    let processed: Vec<(String, u64, u64)> = Vec::new();
+    // keep a list of files that have been processed, their old and new sizes,
+    // and the crf picked for them if we searched for one
+    let processed: Vec<(PathBuf, u64, u64, Option<quality::Choice>)> = Vec::new();
     let shared_processed = Arc::new(Mutex::new(processed));
+    // and the ones whose encodes didn't pass verification, with the reason why
+    let rejected: Vec<(PathBuf, error::Error)> = Vec::new();
+    let shared_rejected = Arc::new(Mutex::new(rejected));
+    let shared_failed = Arc::new(Mutex::new(failed));
+    // and how many were stopped or never started because of ctrl-c
+    #[allow(clippy::mutex_integer)]
+    let interrupted = Arc::new(Mutex::new(0_usize));
+    // remember what happens to each file across runs, unless nothing's going to change
+    let journal = if matches.is_present("dry-run") || matches.is_present("trial") {
+        None
+    } else {
+        match journal::Journal::open() {
+            Ok(journal) => Some(journal),
+            Err(e) if matches.is_present("resume") => {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            }
+            Err(e) => {
+                sink.warn(&format!("{e}, this run can't be resumed"));
+                None
+            }
+        }
+    };
 
-    // let user know if dry run is enabled
+    // let user know if dry run or trial mode is enabled
+    if matches.is_present("dry-run") {
+        sink.say("dry run enabled, nothing will be encoded or modified.");
+    } else if matches.is_present("trial") {
+        sink.say("trial run enabled, files will be encoded but the originals kept.");
+    }
+    if matches.is_present("dry-run") || matches.is_present("trial") {
+        sink.say(format!(
+            "jobs: {jobs} at a time, {encoder_threads} encoder thread{} each",
+            if encoder_threads == 1 { "" } else { "s" }
+        ));
+    }
+    if (matches.is_present("dry-run") || matches.is_present("trial"))
+        && !matches.is_present("images")
+    {
+        sink.say(format!("video: {video}"));
+        sink.say(format!("audio: {audio}"));
+        if let Some(target) = target_quality {
+            sink.say(format!(
+                "quality: searching for the highest crf with {metric} >= {target}"
+            ));
+        }
+    }
+    // a dry run only shows what would happen
     if matches.is_present("dry-run") {
-        println!("dry run enabled, no files will be modified.");
+        print_plan(&matches, &video, &audio, &files);
+        return;
+    }
+    // open the backup directory before anything gets replaced
+    let backups = matches.value_of_os("keep-originals").map(|dir| {
+        backup::Backups::open(Path::new(dir)).unwrap_or_else(|e| {
+            clap::Error::with_description(
+                &format!("failed to open backup directory: {e}"),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit()
+        })
+    });
+    if let Some(backups) = &backups {
+        sink.say(format!(
+            "originals will be moved to {}.",
+            backups.dir().display()
+        ));
     }
+    if matches.is_present("trash") {
+        sink.say("originals will be moved to the trash.");
+    }
+    // stop cleanly on ctrl-c, so the summary still gets printed
+    interrupt::install(matches.value_of("on-interrupt") == Some("kill"))
+        .unwrap_or_else(|e| error::setup(format!("failed to set up the ctrl-c handler: {e}")));
     // starts a timer
     let start = std::time::Instant::now();
 
-    // creates a progress bar
-    let pb = ProgressBar::new(files.len() as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-            .expect("failed to set progress bar template")
-            .progress_chars("##-"),
-    );
-    pb.enable_steady_tick(Duration::from_millis(100));
-    let tmp = Builder::new()
-        .prefix("decoreco")
-        .tempdir()
-        .expect("failed to maek temp dir");
+    // creates the progress bars
+    let progress = progress::Progress::new(files.len(), &sink);
+    let pb = &progress.overall;
+    let workspace = workspace::Workspace::new(matches.value_of_os("tmp-dir").map(Path::new))
+        .unwrap_or_else(|e| {
+            error::setup(error::Error::Io(format!(
+                "failed to make temp dir: {e}. does --tmp-dir exist?"
+            )))
+        });
+    // warn about anything that can't fit even if it's the only thing running
+    let too_big = workspace::too_big(&workspace, &files);
+    if !too_big.is_empty() {
+        let warning = format!(
+            "{} file{} bigger than the free space to encode {} in, and will fail:",
+            too_big.len(),
+            if too_big.len() == 1 { " is" } else { "s are" },
+            if too_big.len() == 1 { "it" } else { "them" }
+        );
+        if sink.json() {
+            let files: Vec<String> = too_big.iter().map(|f| f.display().to_string()).collect();
+            sink.warn(&format!("{warning} {}", files.join(", ")));
+        } else {
+            progress.println(warning.yellow());
+            for file in too_big {
+                progress.println(format!("  {}", file.display()));
+            }
+        }
+    }
+    let record = |file: &Path, outcome: report::Action, sizes: Option<(u64, u64)>| {
+        if let Some(journal) = &journal {
+            if let Err(e) = journal.record(file, outcome, sizes) {
+                progress.warn(&format!("failed to update the journal: {e}"));
+            }
+        }
+    };
+    // prints an error straight away and remembers it for the summary and the report
+    let fail = |entry: &mut report::Entry, err: error::Error| {
+        // anything that went wrong because ctrl-c killed it didn't really fail
+        if interrupt::killed() {
+            *(interrupted.lock().expect("poisoned")) += 1;
+            entry.action = report::Action::Interrupted;
+            return;
+        }
+        progress.println(err.message().red());
+        record(&entry.path, report::Action::Failed, None);
+        entry.action = report::Action::Failed;
+        entry.set_error(&err);
+        shared_failed
+            .lock()
+            .expect("poisoned")
+            .push((entry.path.clone(), err));
+    };
     #[allow(clippy::mutex_integer)]
     let saved_size = Arc::new(Mutex::new(0_u64));
     #[allow(clippy::mutex_integer)]
     let total_size = Arc::new(Mutex::new(0_u64));
+    #[allow(clippy::mutex_integer)]
+    let skipped = Arc::new(Mutex::new(0_usize));
+    #[allow(clippy::mutex_integer)]
+    let resumed = Arc::new(Mutex::new(0_usize));
+    #[allow(clippy::mutex_integer)]
+    let tagged = Arc::new(Mutex::new(0_usize));
 
-    // iterates through the files
-    files.par_iter().enumerate().for_each(|(i, file)| {
-        let i = i.to_string() + "." + file.split('.').last().expect("no file ext");
+    // probe everything up front, so the progress bar knows how much work there is
+    pb.set_message("probing files...");
+    let infos: Vec<Option<Result<probe::MediaInfo, String>>> = files
+        .par_iter()
+        .map(|file| {
+            let resumed = matches.is_present("resume")
+                && journal.as_ref().and_then(|j| j.handled(file)).is_some();
+            (!matches.is_present("images") && !resumed).then(|| probe::probe(file))
+        })
+        .collect();
+    // videos are weighed by their duration in milliseconds, and images by their size
+    let weights: Vec<u64> = files
+        .iter()
+        .zip(&infos)
+        .map(|(file, info)| match info {
+            Some(Ok(info)) => {
+                let skip = (!matches.is_present("include-tagged") && info.tag.is_some())
+                    || (!matches.is_present("force") && info.matches(&video, &audio));
+                // assume a minute if ffprobe doesn't know
+                match info.duration {
+                    _ if skip => 0,
+                    Some(duration) => (duration * 1000.0) as u64,
+                    None => 60_000,
+                }
+            }
+            None if matches.is_present("images") => std::fs::metadata(file).map_or(0, |m| m.len()),
+            _ => 0,
+        })
+        .collect();
+    pb.set_length(weights.iter().sum());
+    pb.set_message("");
 
-        match decoreco(&matches, &tmp, &i, file) {
-            Ok(()) => {
-                let new_path = tmp
-                    .path()
-                    .join(i.clone())
-                    .to_str()
-                    .expect("failed to get new path")
-                    .to_string();
+    // failed encodes get their whole output saved here
+    let logs = diagnose::Logs::start();
+    // processes a single file, filling in its report entry as it goes
+    let process = |index: usize, file: &PathBuf, entry: &mut report::Entry| {
+        let job = progress.start(file, weights[index]);
+        // name the temp file after its index so odd filenames can't clash or escape the tempdir
+        let mut i = PathBuf::from(index.to_string());
+        if let Some(ext) = file.extension() {
+            i.set_extension(ext);
+        }
+
+        // don't start anything new once ctrl-c has been pressed
+        if interrupt::stopping() {
+            *(interrupted.lock().expect("poisoned")) += 1;
+            entry.action = report::Action::Interrupted;
+            return;
+        }
+
+        // leave files an earlier run already dealt with
+        if matches.is_present("resume") {
+            if let Some(outcome) = journal.as_ref().and_then(|j| j.handled(file)) {
+                progress.message(format!(
+                    "{} {}",
+                    format!("already {outcome} ").yellow(),
+                    file.display()
+                ));
+                *(resumed.lock().expect("poisoned")) += 1;
+                entry.action = report::Action::Resumed;
+                return;
+            }
+        }
+
+        // work out what's in the file first, so we don't re-encode things that are already fine
+        let info = if matches.is_present("images") {
+            None
+        } else {
+            let probed = infos[index].clone();
+            let info = match probed.unwrap_or_else(|| probe::probe(file)) {
+                Ok(info) => info,
+                Err(str) => {
+                    fail(
+                        entry,
+                        error::Error::Probe(format!("failed to probe: {str}")),
+                    );
+                    return;
+                }
+            };
+            entry.codecs = info
+                .streams
+                .iter()
+                .filter(|s| {
+                    s.kind == probe::StreamKind::Audio
+                        || (s.kind == probe::StreamKind::Video && !s.attached_pic)
+                })
+                .map(|s| s.codec.clone())
+                .collect();
+            entry.duration = info.duration;
+            if !matches.is_present("include-tagged") {
+                if let Some(tag) = &info.tag {
+                    progress.message(format!(
+                        "{} {}",
+                        format!("already encoded ({tag}) ").yellow(),
+                        file.display()
+                    ));
+                    record(file, report::Action::Skipped, None);
+                    *(tagged.lock().expect("poisoned")) += 1;
+                    entry.action = report::Action::Tagged;
+                    return;
+                }
+            }
+            if !matches.is_present("force") && info.matches(&video, &audio) {
+                progress.message(format!(
+                    "{} {}",
+                    format!("already {}/{} ", video.codec, audio.codec).yellow(),
+                    file.display()
+                ));
+                record(file, report::Action::Skipped, None);
+                *(skipped.lock().expect("poisoned")) += 1;
+                entry.action = report::Action::Skipped;
+                return;
+            }
+            Some(info)
+        };
+
+        // find somewhere to encode to, waiting for other jobs to finish if there's no room
+        let dir = match workspace.dir_for(file) {
+            Ok(dir) => dir,
+            Err(err) => {
+                fail(
+                    entry,
+                    error::Error::Io(format!("failed to make temp dir: {err}")),
+                );
+                return;
+            }
+        };
+        let size = std::fs::metadata(file).map_or(0, |m| m.len());
+        let _reservation = match workspace.reserve(&dir, size) {
+            Ok(reservation) => reservation,
+            Err(err) => {
+                fail(entry, error::Error::Io(err));
+                return;
+            }
+        };
+        // waiting for space can take a while, so check again
+        if interrupt::stopping() {
+            *(interrupted.lock().expect("poisoned")) += 1;
+            entry.action = report::Action::Interrupted;
+            return;
+        }
+        let output = workspace::Output::new(&dir, &i);
+
+        let encode_start = std::time::Instant::now();
+        let encoded = decoreco(
+            &matches,
+            &video,
+            &audio,
+            output.path(),
+            file,
+            info.as_ref(),
+            &job,
+            entry,
+        );
+        entry.encode_time = Some(encode_start.elapsed().as_secs_f64());
+        match encoded {
+            Ok(choice) => {
+                let new_path = output.path();
+                // make sure the encode is complete before it gets anywhere near the original
+                job.status("verifying");
+                let decode = !matches.is_present("no-decode-check");
+                let verified = match &info {
+                    Some(info) => verify::verify(file, info, new_path, decode, video.threads),
+                    // images only have their originals deleted, so they need checking too
+                    None => verify::verify_image(file, new_path, decode),
+                };
+                if let Err(mut err) = verified {
+                    if interrupt::killed() {
+                        *(interrupted.lock().expect("poisoned")) += 1;
+                        entry.action = report::Action::Interrupted;
+                        return;
+                    }
+                    // keep everything the decoder said, like for encoder failures
+                    if let error::Error::Encoder(failure) = &mut err {
+                        logs.keep(failure, index);
+                    }
+                    progress.message(format!("{} {}", "rejected ".red(), file.display()));
+                    record(file, report::Action::Rejected, None);
+                    entry.action = report::Action::Rejected;
+                    entry.set_error(&err);
+                    shared_rejected
+                        .lock()
+                        .expect("poisoned")
+                        .push((file.clone(), err));
+                    return;
+                }
                 // check if file is bigger than the original
-                let orig_file_size: u64 = String::from_utf8(
-                    Command::new("stat")
-                        .arg("--printf=%s")
-                        .arg(file)
-                        .output()
-                        .expect("failed to read size")
-                        .stdout,
-                )
-                .expect("failed to parse size into utf-8")
-                .trim()
-                .parse()
-                .expect("failed to parse size as u64");
-
-                let new_file_size: u64 = String::from_utf8(
-                    Command::new("stat")
-                        .arg("--printf=%s")
-                        .arg(
-                            tmp.path()
-                                .join(i.clone())
-                                .to_str()
-                                .expect("failed to get path of tmpdir"),
-                        )
-                        .output()
-                        .expect("failed to read size")
-                        .stdout,
-                )
-                .expect("failed to parse size into utf-8")
-                .trim()
-                .parse()
-                .expect("failed to parse size as u64");
-                pb.inc(1);
+                let sizes = std::fs::metadata(file)
+                    .and_then(|orig| Ok((orig.len(), std::fs::metadata(new_path)?.len())));
+                let (orig_file_size, new_file_size) = match sizes {
+                    Ok(sizes) => sizes,
+                    Err(err) => {
+                        fail(
+                            entry,
+                            error::Error::Io(format!("failed to read file sizes: {err}")),
+                        );
+                        return;
+                    }
+                };
+                entry.old_size = Some(orig_file_size);
+                entry.new_size = Some(new_file_size);
 
                 if new_file_size < orig_file_size {
-                    pb.set_message(format!(
+                    progress.message(format!(
                         "{} {}",
                         format!(
                             "smaller by {}% ",
                             100 - (new_file_size * 100) / orig_file_size
                         )
                         .green(),
-                        file
+                        file.display()
                     ));
-                    *(saved_size.lock().expect("poisoned")) += orig_file_size - new_file_size;
-                    *(total_size.lock().expect("poisoned")) += orig_file_size;
-                    // move the file to the original location if it's not a dry run
-                    if !matches.is_present("dry-run") {
-                        Command::new("mv")
-                            .arg(new_path)
-                            .arg(
-                                // if it's an img make sure to add the img ext
-                                if matches.is_present("images") {
-                                    format!("{}.jxl", file)
-                                } else {
-                                    (*file).to_string()
-                                },
-                            )
-                            .output()
-                            .expect("failed to add extension");
-                        if matches.is_present("images") {
-                            Command::new("rm")
-                                .arg(file)
-                                .output()
-                                .expect("failed to remove old file");
+                    // move the file to the original location if it's not a trial run
+                    entry.action = report::Action::Encoded;
+                    if !matches.is_present("trial") {
+                        // if it's an img make sure to add the img ext
+                        let target = if matches.is_present("images") {
+                            let mut jxl = file.clone().into_os_string();
+                            jxl.push(".jxl");
+                            PathBuf::from(jxl)
+                        } else {
+                            file.clone()
+                        };
+                        let original =
+                            (!matches.is_present("no-preserve")).then_some(file.as_path());
+                        // hold on to the original first, if we're keeping them
+                        let kept = match backups.as_ref().map(|b| b.keep(file)).transpose() {
+                            Ok(kept) => kept,
+                            Err(err) => {
+                                let err = format!("failed to back up the original: {err}");
+                                fail(entry, error::Error::Commit(err));
+                                return;
+                            }
+                        };
+                        let trashed = if matches.is_present("trash") {
+                            match trash::stash(file) {
+                                Ok(trashed) => Some(trashed),
+                                Err(err) => {
+                                    let err = format!("failed to trash the original: {err}");
+                                    fail(entry, error::Error::Commit(err));
+                                    return;
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        // the kept or trashed copy can only go if the original is still there
+                        let discard =
+                            |kept: Option<backup::Kept>, trashed: Option<trash::Trashed>| {
+                                if let Some(kept) = kept {
+                                    let _ = kept.discard();
+                                }
+                                if let Some(trashed) = trashed {
+                                    let _ = trashed.discard();
+                                }
+                            };
+                        // mark it so later runs leave it alone, if the filesystem lets us
+                        let tag = tag_value(&matches, &video, file, choice);
+                        let unfinished =
+                            match commit::replace(new_path, &target, original, Some(&tag)) {
+                                Ok(()) => None,
+                                Err(commit::Error::Untouched(err)) => {
+                                    discard(kept, trashed);
+                                    let err = format!("failed to replace the original: {err}");
+                                    fail(entry, error::Error::Commit(err));
+                                    return;
+                                }
+                                // too late to put it back, so hold on to the original for later
+                                Err(commit::Error::Replaced(err)) => Some(err),
+                            };
+                        // images get a new name, so the original has to go separately
+                        if matches.is_present("images") && unfinished.is_none() {
+                            if let Err(err) = std::fs::remove_file(file) {
+                                discard(kept, trashed);
+                                let err = format!("failed to remove the original: {err}");
+                                fail(entry, error::Error::Commit(err));
+                                return;
+                            }
+                        }
+                        if let (Some(backups), Some(kept)) = (&backups, kept) {
+                            if let Err(err) = backups.record(kept, &target) {
+                                let err = format!(
+                                    "replaced, but failed to add the original to the backup manifest: {err}"
+                                );
+                                fail(entry, error::Error::Commit(err));
+                                return;
+                            }
+                        }
+                        if let Some(err) = unfinished {
+                            let err = format!(
+                                "replaced, but the replacement might not survive a crash: {err}"
+                            );
+                            fail(entry, error::Error::Commit(err));
+                            return;
                         }
+                        record(
+                            &target,
+                            report::Action::Replaced,
+                            Some((orig_file_size, new_file_size)),
+                        );
+                        entry.action = report::Action::Replaced;
                     }
+                    *(saved_size.lock().expect("poisoned")) += orig_file_size - new_file_size;
+                    *(total_size.lock().expect("poisoned")) += orig_file_size;
                     // add the file to the list of processed files
                     let mut processed = shared_processed.lock().expect("poisoned");
 
-                    processed.push(((*file).to_string(), orig_file_size, new_file_size));
+                    processed.push((file.clone(), orig_file_size, new_file_size, choice));
                 } else {
-                    pb.set_message(format!(
+                    progress.message(format!(
                         "{} {}",
                         format!(" larger by {}% ", (orig_file_size * 100) / new_file_size).red(),
-                        file
+                        file.display()
                     ));
+                    record(
+                        file,
+                        report::Action::Larger,
+                        Some((orig_file_size, new_file_size)),
+                    );
+                    entry.action = report::Action::Larger;
                 }
 
                 // updates the progress bar
             }
-            Err(str) => {
-                let thing = format!("failed to decoreco: {str}").red();
-                pb.inc(1);
-                println!("{thing}");
+            Err(mut err) => {
+                // keep everything the encoder said somewhere it can be looked at later
+                if let error::Error::Encoder(failure) = &mut err {
+                    if !interrupt::killed() {
+                        logs.keep(failure, index);
+                    }
+                }
+                fail(entry, err);
             }
         }
-    });
+    };
+    // iterates through the files, collecting their entries in the order they were found
+    let processed_entries: Vec<report::Entry> = files
+        .par_iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let mut entry = report::Entry::new(file);
+            process(index, file, &mut entry);
+            sink.finished(&entry);
+            entry
+        })
+        .collect();
 
-    // finishes the progress bar
-    pb.finish_and_clear();
+    // finishes the progress bars
+    progress.finish();
+    unprocessed.extend(processed_entries);
+    let entries = unprocessed;
+    let exit_code = error::exit_code(&entries, interrupt::stopping());
+    let write_report = || {
+        if let Some(path) = matches.value_of_os("report") {
+            let format = matches.value_of("report-format").expect("no report format");
+            match report::write(Path::new(path), format, &entries) {
+                Ok(()) => sink.say(format!("report written to {}.", Path::new(path).display())),
+                Err(err) => sink.warn(&err),
+            }
+        }
+    };
+    // programs get the summary as an event instead
+    if sink.json() {
+        let saved_size = *saved_size.lock().expect("poisoned");
+        let total_size = *total_size.lock().expect("poisoned");
+        let mut actions = std::collections::BTreeMap::new();
+        for entry in &entries {
+            *actions.entry(entry.action).or_insert(0) += 1;
+        }
+        sink.emit(&events::Event::RunSummary {
+            actions,
+            old_size: total_size,
+            new_size: total_size - saved_size,
+            saved: saved_size,
+            elapsed: start.elapsed().as_secs_f64(),
+            exit_code,
+        });
+        write_report();
+        if let Err(e) = workspace.close() {
+            sink.warn(&format!("failed to remove temp dirs: {e}"));
+        }
+        std::process::exit(exit_code);
+    }
     // print finished in rainbows
     println!("done.");
 
-    println!(
-        "{}",
-        { "-".repeat(term_size::dimensions().expect("failed to get term size").0) }.bold()
-    );
+    println!("{}", "-".repeat(term_width().unwrap_or(80)).bold());
 
     // if saved_size == 0 {
     let saved_size = *saved_size.lock().expect("poisoned");
     let total_size = *total_size.lock().expect("poisoned");
+    let skipped = *skipped.lock().expect("poisoned");
+    if skipped != 0 {
+        println!(
+            "skipped {skipped} file{} already in the target codecs.",
+            if skipped == 1 { "" } else { "s" }
+        );
+    }
+    let interrupted = *interrupted.lock().expect("poisoned");
+    if interrupted != 0 {
+        println!(
+            "{}",
+            format!(
+                "interrupted, {interrupted} file{} left as {}. run again with --resume to carry on.",
+                if interrupted == 1 { " was" } else { "s were" },
+                if interrupted == 1 { "it was" } else { "they were" }
+            )
+            .yellow()
+        );
+    }
+    let tagged = *tagged.lock().expect("poisoned");
+    if tagged != 0 {
+        println!(
+            "skipped {tagged} file{} decoreco already encoded.",
+            if tagged == 1 { "" } else { "s" }
+        );
+    }
+    let resumed = *resumed.lock().expect("poisoned");
+    if resumed != 0 {
+        println!(
+            "skipped {resumed} file{} handled by an earlier run.",
+            if resumed == 1 { "" } else { "s" }
+        );
+    }
+    let rejected = shared_rejected.lock().expect("poisoned").clone();
+    if !rejected.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "rejected {} file{} that failed verification, the originals were kept:",
+                rejected.len(),
+                if rejected.len() == 1 { "" } else { "s" }
+            )
+            .red()
+        );
+        for (file, err) in rejected {
+            match &err {
+                error::Error::Encoder(failure) => {
+                    println!("  {}: {}, {}", file.display(), failure.reason, detail(&err));
+                }
+                _ => println!("  {}: {}", file.display(), detail(&err)),
+            }
+        }
+    }
+    let failed = shared_failed.lock().expect("poisoned").clone();
+    if !failed.is_empty() {
+        println!(
+            "{}",
+            format!(
+                "failed to process {} file{}:",
+                failed.len(),
+                if failed.len() == 1 { "" } else { "s" }
+            )
+            .red()
+        );
+        // group them by what went wrong, so e.g. a missing encoder is only explained once
+        let mut groups: std::collections::BTreeMap<&str, Vec<&(PathBuf, error::Error)>> =
+            std::collections::BTreeMap::new();
+        for failure in &failed {
+            groups.entry(failure.1.reason()).or_default().push(failure);
+        }
+        for (reason, group) in groups {
+            let count = format!(
+                "{reason}, {} file{}",
+                group.len(),
+                if group.len() == 1 { "" } else { "s" }
+            );
+            match group[0].1.hint() {
+                Some(hint) => println!("  {}. {hint}", count.bold()),
+                None => println!("  {}:", count.bold()),
+            }
+            for (file, err) in group {
+                println!("    {}: {}", file.display(), detail(err));
+            }
+        }
+    }
+    if let Some(backups) = &backups {
+        let dir = shell_quote(backups.dir().as_os_str());
+        println!("originals were moved to {dir}, undo with `decoreco restore {dir}`.");
+    }
+    // with --resume, earlier runs' savings are probably what the user wants to know about
+    if let Some(journal) = journal.as_ref().filter(|_| matches.is_present("resume")) {
+        let under: Vec<PathBuf> = match matches.value_of_os("path") {
+            Some(path) if !matches.is_present("set") => vec![PathBuf::from(path)],
+            _ => files
+                .iter()
+                .flat_map(|f| {
+                    let mut jxl = f.clone().into_os_string();
+                    jxl.push(".jxl");
+                    [f.clone(), PathBuf::from(jxl)]
+                })
+                .collect(),
+        };
+        let (saved, total) = journal.savings(&under);
+        if saved != 0 {
+            println!(
+                "saved over every run: {} ({}% of original)",
+                humanize_bytes(saved),
+                (saved * 100) / total
+            );
+        }
+    }
     if saved_size == 0 {
         println!("no files were compressed.");
     } else {
@@ -415,18 +1073,33 @@ fn main() {
         println!("files compressed:");
         let mut table = Table::new();
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
-        table.set_titles(row!["file", "old size", "new size", "saved size"]);
+        let mut titles = row!["file", "old size", "new size", "saved size"];
+        if target_quality.is_some() {
+            titles.add_cell(Cell::new(&format!("crf ({metric})")));
+        }
+        table.set_titles(titles);
         let processed = shared_processed.lock().expect("poisoned").clone();
-        for (file, old_size, new_size) in processed {
-            table.add_row(Row::new(vec![
-                Cell::new(&truncate(
-                    &file,
-                    term_size::dimensions().expect("failed to get term size").0 - 60,
+        for (file, old_size, new_size, choice) in processed {
+            let mut cells = vec![
+                Cell::new(&fit(
+                    &file.to_string_lossy(),
+                    if target_quality.is_some() { 80 } else { 60 },
                 )),
                 Cell::new(&humanize_bytes(old_size)).style_spec("br"),
                 Cell::new(&humanize_bytes(new_size)).style_spec("br"),
                 Cell::new(&humanize_bytes(old_size - new_size)).style_spec("br"),
-            ]));
+            ];
+            if target_quality.is_some() {
+                // files where the video was copied never got searched
+                cells.push(
+                    Cell::new(&choice.map_or_else(
+                        || "-".to_string(),
+                        |c| format!("{} ({:.3})", c.crf, c.score),
+                    ))
+                    .style_spec("r"),
+                );
+            }
+            table.add_row(Row::new(cells));
         }
 
         table.set_format(*prettytable::format::consts::FORMAT_BOX_CHARS);
@@ -438,22 +1111,36 @@ fn main() {
             Cell::new(&humanize_bytes(total_size - saved_size)).style_spec("Fgr"),
             Cell::new(&humanize_bytes(saved_size)).style_spec("Fbr"),
         ]));
+        if target_quality.is_some() {
+            table
+                .get_mut_row(table.len() - 1)
+                .expect("no total row")
+                .add_cell(Cell::new(""));
+        }
 
-        table.printstd();
+        print_table(&table);
+        if !matches.is_present("images") {
+            println!("video: {video}");
+            println!("audio: {audio}");
+            if let Some(target) = target_quality {
+                println!("quality: crf searched per file for {metric} >= {target}");
+            }
+        }
 
         // print time elapsed
         let elapsed = start.elapsed();
         println!(
             "took {} total, on average {} per MB",
             time_human(elapsed.as_millis()).green(),
-            time_human(
-                elapsed.as_millis() / (u128::from(total_size).checked_div(1_000_000).unwrap_or(1))
-            )
-            .green(),
+            time_human(elapsed.as_millis() / (u128::from(total_size) / 1_000_000).max(1)).green(),
         );
     }
+    write_report();
     // delete tempdir
-    tmp.close().expect("failed to remove tempdir");
+    if let Err(e) = workspace.close() {
+        sink.warn(&format!("failed to remove temp dirs: {e}"));
+    }
+    std::process::exit(exit_code);
 }
 
 /// Transcodes/recompresses a file using the given options.
@@ -461,79 +1148,491 @@ fn main() {
 /// # Arguments
 ///
 /// * `matches` - The `ArgMatches` struct from clap.
-/// * `tmp` - A `TempDir` to store the new file in.
-/// * `i` - The index of the file.
+/// * `video` - The encoder and quality settings for video streams.
+/// * `audio` - The encoder, bitrate and channel settings for audio streams.
+/// * `arg` - Where to write the new file.
 /// * `file` - The path to the file.
+/// * `info` - What ffprobe found in the file, used to pick which streams to copy. `None` for images.
+/// * `job` - The file's progress bar, which follows ffmpeg's progress.
+/// * `entry` - The file's report entry, which gets the encoder's exit status.
 ///
 /// # Returns
 ///
-/// * `Ok(())` if the command succeeds.
-/// * `Err(String)` if the command fails, with the error message.
-///
-/// # Panics
-///
-/// Panics if the command fails.
+/// * `Ok(Some(Choice))` if the command succeeds after a quality search, with the crf it picked.
+/// * `Ok(None)` if the command succeeds without a quality search.
+/// * `Err(Error::Encode)` if the search or the command can't be run, with the error message.
+/// * `Err(Error::Encoder)` if the search or the command fails, with what probably went wrong.
+#[allow(clippy::too_many_arguments)]
 fn decoreco(
     matches: &clap::ArgMatches<'_>,
-    tmp: &tempfile::TempDir,
-    i: &str,
-    file: &str,
-) -> Result<(), String> {
-    let binding = tmp.path().join(i);
-    let arg = binding.to_str().expect("failed to get path");
+    video: &codec::VideoSettings,
+    audio: &codec::AudioSettings,
+    arg: &Path,
+    file: &Path,
+    info: Option<&probe::MediaInfo>,
+    job: &progress::Job<'_>,
+    entry: &mut report::Entry,
+) -> Result<Option<quality::Choice>, error::Error> {
+    let mut choice = None;
+    let encode_err = |err: String| error::Error::Encode(format!("failed to encode: {err}"));
 
     let res = if matches.is_present("images") {
+        job.status("cjxl");
+        match interrupt::output(
+            &mut encode_command(matches, video, audio, file, arg, None, false)
+                .map_err(encode_err)?,
+        ) {
+            Ok(it) => it,
+            Err(err) => return Err(encode_err(format!("failed to run cjxl: {err}"))),
+        }
+    } else {
+        let info = info
+            .ok_or_else(|| format!("{} hasn't been probed", file.display()))
+            .map_err(encode_err)?;
+        // search for the crf to use if there's a quality target and something to encode
+        let video = match (
+            matches.value_of("target-quality"),
+            info.streams.iter().find(|s| video.needs_encode(s)),
+        ) {
+            (Some(target), Some(stream)) => {
+                job.status("crf search");
+                let found = quality::search(
+                    file,
+                    stream.index,
+                    info.duration,
+                    video,
+                    quality::Metric::from_name(matches.value_of("metric").expect("no metric")),
+                    target.parse().expect("not a number?"),
+                    matches
+                        .value_of("quality-samples")
+                        .expect("no sample count")
+                        .parse()
+                        .expect("not a usize?"),
+                    arg,
+                )?;
+                choice = Some(found);
+                codec::VideoSettings {
+                    crf: found.crf,
+                    ..video.clone()
+                }
+            }
+            _ => video.clone(),
+        };
+        job.status("starting");
+        match interrupt::output_with(
+            &mut encode_command(matches, &video, audio, file, arg, Some(info), true)
+                .map_err(encode_err)?,
+            |line| job.ffmpeg(line),
+        ) {
+            Ok(it) => it,
+            Err(err) => return Err(encode_err(format!("failed to run ffmpeg: {err}"))),
+        }
+    };
+
+    entry.exit_status = res.status.code();
+    if !res.status.success() {
+        // work out what went wrong from stderr, rather than dumping all of it
+        return Err(error::Error::Encoder(Box::new(diagnose::Failure::new(
+            "encode",
+            "encode",
+            file,
+            String::from_utf8_lossy(&res.stderr).into_owned(),
+        ))));
+    }
+    Ok(choice)
+}
+
+/// The line of an error worth showing in the summary, with where its log is if it has one.
+fn detail(err: &error::Error) -> String {
+    match err {
+        error::Error::Encoder(failure) => match &failure.log {
+            Some(log) => format!("{} (log: {})", failure.detail, log.display()),
+            None => failure.detail.clone(),
+        },
+        // the last line is usually the one that says what actually went wrong
+        _ => err
+            .message()
+            .lines()
+            .rev()
+            .find(|l| !l.trim().is_empty())
+            .unwrap_or("")
+            .trim()
+            .to_string(),
+    }
+}
+
+/// Works out how many files to encode at once, and how many threads each encoder gets.
+///
+/// Whichever of the two isn't given is worked out from the other and the core count.
+/// If neither is, images get a job per core with a thread each, since cjxl doesn't
+/// scale well, and videos get a job per 8 cores, since ffmpeg's encoders do.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct from clap.
+///
+/// # Returns
+///
+/// * The number of jobs and the number of encoder threads.
+fn concurrency(matches: &clap::ArgMatches<'_>) -> (usize, usize) {
+    let cores = std::thread::available_parallelism().map_or(1, std::num::NonZero::get);
+    let parse = |name| {
+        matches
+            .value_of(name)
+            .map(|n| n.parse::<usize>().expect("not a usize?"))
+    };
+    // -t 0 was the old way of leaving it to us
+    let jobs = parse("jobs").or_else(|| parse("threads").filter(|&n| n != 0));
+    match (jobs, parse("encoder-threads")) {
+        (Some(jobs), Some(threads)) => (jobs, threads),
+        (Some(jobs), None) => (jobs, (cores / jobs).max(1)),
+        (None, Some(threads)) => ((cores / threads).max(1), threads),
+        (None, None) if matches.is_present("images") => (cores, 1),
+        (None, None) => {
+            let jobs = (cores / 8).max(1);
+            (jobs, (cores / jobs).max(1))
+        }
+    }
+}
+
+/// Works out the tag to mark a replaced file with.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct from clap.
+/// * `video` - The video settings the file was encoded with.
+/// * `file` - The path to the original file.
+/// * `choice` - The crf the quality search picked, if there was one.
+fn tag_value(
+    matches: &clap::ArgMatches<'_>,
+    video: &codec::VideoSettings,
+    file: &Path,
+    choice: Option<quality::Choice>,
+) -> String {
+    if matches.is_present("images") {
+        // pngs are encoded losslessly, and jpegs are losslessly recompressed by default
+        let how = if discover::has_extension(file, &["png"]) {
+            "lossless"
+        } else {
+            "jpeg"
+        };
+        tag::value("jxl", how)
+    } else {
+        tag::value(
+            &video.codec,
+            &choice.map_or(video.crf, |c| c.crf).to_string(),
+        )
+    }
+}
+
+/// Builds the command that encodes a file, either cjxl for images or ffmpeg for videos.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct from clap.
+/// * `video` - The encoder and quality settings for video streams.
+/// * `audio` - The encoder, bitrate and channel settings for audio streams.
+/// * `file` - The path to the file.
+/// * `out` - Where to write the encoded file.
+/// * `info` - What ffprobe found in the file. `None` for images.
+/// * `progress` - Whether ffmpeg should write its progress to stdout for `Job::ffmpeg`.
+///
+/// # Returns
+///
+/// * `Ok(Command)` ready to run.
+/// * `Err(String)` if the file can't be encoded, with the error message.
+fn encode_command(
+    matches: &clap::ArgMatches<'_>,
+    video: &codec::VideoSettings,
+    audio: &codec::AudioSettings,
+    file: &Path,
+    out: &Path,
+    info: Option<&probe::MediaInfo>,
+    progress: bool,
+) -> Result<Command, String> {
+    if matches.is_present("images") {
         let losslessimg = // extract extension and then use match
-                    match file.split('.').last().expect("no extension?") {
-                        "png" => true,
-                        "jpg" | "jpeg" => false,
+                    match file
+                        .extension()
+                        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+                        .as_deref()
+                    {
+                        Some("png") => true,
+                        Some("jpg" | "jpeg") => false,
                         // "avif" => Command::new(program)
                         _ => {
-                            return Err(format!("{file} is not a supported image format"));
+                            return Err(format!(
+                                "{} is not a supported image format",
+                                file.display()
+                            ));
                         }
                     };
         let mut cmd = Command::new("cjxl");
-        let cmd: &mut Command = if losslessimg {
-            cmd.arg("-d").arg("0")
-        } else {
-            &mut cmd
-        };
-        match cmd.arg(file).arg(arg).output() {
-            Ok(it) => it,
-            Err(err) => return Err(err.to_string()),
+        if losslessimg {
+            cmd.arg("-d").arg("0");
         }
+        cmd.arg("--num_threads").arg(video.threads.to_string());
+        cmd.arg(file).arg(out);
+        Ok(cmd)
     } else {
-        match Command::new("ffmpeg")
-            .arg("-i")
+        let info = info.ok_or_else(|| format!("{} hasn't been probed", file.display()))?;
+        let mut cmd = Command::new("ffmpeg");
+        if progress {
+            cmd.args(["-nostats", "-progress", "pipe:1"]);
+        }
+        cmd.arg("-i")
             .arg(file)
-            .arg("-c:v")
-            .arg(matches.value_of("video-codec").expect("no video codec"))
-            .arg("-c:a")
-            .arg(matches.value_of("audio-codec").expect("no audio codec"))
-            // keep subs
-            .arg("-c:s")
-            .arg("copy")
+            .args(stream_args(video, audio, info))
+            .arg("-threads")
+            .arg(video.threads.to_string())
             // keep metadata
             .arg("-map_metadata")
             .arg("0")
-            .arg("-y")
-            .arg(arg)
-            .output()
-        {
-            Ok(it) => it,
-            Err(err) => return Err(err.to_string()),
+            // and tag it as ours, which mp4 and mov only keep if asked to
+            .arg("-metadata")
+            .arg(format!(
+                "{}={}",
+                tag::METADATA_KEY,
+                tag::value(&video.codec, &video.crf.to_string())
+            ));
+        if discover::has_extension(out, &["mp4", "m4v", "mov", "3gp"]) {
+            cmd.arg("-movflags").arg("+use_metadata_tags");
         }
-    };
+        cmd.arg("-y").arg(out);
+        Ok(cmd)
+    }
+}
 
-    if !res.status.success() {
-        // return path of faulty file and stderr
-        return Err(format!(
-            "{}\n{}\n",
-            file,
-            String::from_utf8(res.stderr).expect("failed to convert error to UTF-8")
-        ));
+/// Builds the ffmpeg arguments that map every stream and pick an encoder for each one.
+///
+/// Video and audio streams that are already how we'd encode them are copied as-is, so
+/// e.g. an HEVC video next to a PCM track only gets its audio re-encoded. Everything
+/// else (subtitles, attachments, data) is always copied.
+///
+/// # Arguments
+///
+/// * `video` - The encoder and quality settings for video streams.
+/// * `audio` - The encoder, bitrate and channel settings for audio streams.
+/// * `info` - What ffprobe found in the file.
+///
+/// # Returns
+///
+/// * A list of `-map` and `-c` arguments for ffmpeg.
+fn stream_args(
+    video: &codec::VideoSettings,
+    audio: &codec::AudioSettings,
+    info: &probe::MediaInfo,
+) -> Vec<String> {
+    // keep every stream, not just the ones ffmpeg would pick by default
+    let mut args: Vec<String> = ["-map", "0", "-copy_unknown", "-c", "copy"]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    // with everything mapped, output stream numbers are the same as the input ones
+    for stream in &info.streams {
+        if video.needs_encode(stream) {
+            args.extend(video.args(stream.index));
+        } else if audio.needs_encode(stream) {
+            args.extend(audio.args(stream));
+        }
+        // everything else falls through to the -c copy above
+    }
+    args
+}
+
+/// Quotes an argument so a printed command line can be pasted into a shell.
+///
+/// # Arguments
+///
+/// * `arg` - The argument to quote.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(shell_quote(OsStr::new("-c:v")), "-c:v");
+/// assert_eq!(shell_quote(OsStr::new("it's.mkv")), "'it'\\''s.mkv'");
+/// ```
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c))
+    {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Prints what a run would do to each file, without encoding anything.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct from clap.
+/// * `video` - The encoder and quality settings for video streams.
+/// * `audio` - The encoder, bitrate and channel settings for audio streams.
+/// * `files` - The files that would be processed.
+fn print_plan(
+    matches: &clap::ArgMatches<'_>,
+    video: &codec::VideoSettings,
+    audio: &codec::AudioSettings,
+    files: &[PathBuf],
+) {
+    let images = matches.is_present("images");
+    // probing is the slow part, so do it in parallel and print in order afterwards
+    let infos: Vec<Option<Result<probe::MediaInfo, String>>> = files
+        .par_iter()
+        .map(|file| (!images).then(|| probe::probe(file)))
+        .collect();
+    for (n, (file, info)) in files.iter().zip(infos).enumerate() {
+        println!("{}", file.display().to_string().bold());
+        let info = match info.transpose() {
+            Ok(info) => info,
+            Err(err) => {
+                println!("  {}", format!("failed to probe: {}", err.trim()).red());
+                continue;
+            }
+        };
+        if let Some(info) = &info {
+            let codecs = info
+                .streams
+                .iter()
+                .filter(|s| {
+                    s.kind == probe::StreamKind::Audio
+                        || (s.kind == probe::StreamKind::Video && !s.attached_pic)
+                })
+                .map(|s| s.codec.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("  codecs: {codecs}");
+            if let Some(tag) = info
+                .tag
+                .as_ref()
+                .filter(|_| !matches.is_present("include-tagged"))
+            {
+                println!(
+                    "  action: {}",
+                    format!("skip, already encoded by decoreco ({tag})").yellow()
+                );
+                continue;
+            }
+            let encode_video = info.streams.iter().any(|s| video.needs_encode(s));
+            let encode_audio = info.streams.iter().any(|s| audio.needs_encode(s));
+            let action = match (encode_video, encode_audio) {
+                (false, false) if !matches.is_present("force") => {
+                    println!(
+                        "  action: {}",
+                        "skip, already in the target codecs".yellow()
+                    );
+                    continue;
+                }
+                (false, false) => "remux, every stream is copied",
+                (true, false) => "re-encode video, copy audio",
+                (false, true) => "copy video, re-encode audio",
+                (true, true) => "re-encode video and audio",
+            };
+            println!("  action: {action}");
+            if encode_video && matches.is_present("target-quality") {
+                println!(
+                    "  crf: picked per file by --target-quality, shown as {}",
+                    video.crf
+                );
+            }
+        } else {
+            println!("  action: convert to jxl");
+        }
+        // the real output goes in a tempdir that doesn't exist yet, so make up a name for it
+        let dir = match (matches.value_of_os("tmp-dir"), file.parent()) {
+            (Some(tmp), _) => Path::new(tmp).join("decoreco"),
+            (None, Some(parent)) => parent.join(discover::TMP_PREFIX),
+            (None, None) => PathBuf::from(discover::TMP_PREFIX),
+        };
+        let mut out = dir.join(n.to_string());
+        if let Some(ext) = file.extension() {
+            out.set_extension(ext);
+        }
+        match encode_command(matches, video, audio, file, &out, info.as_ref(), false) {
+            Ok(cmd) => println!(
+                "  command: {}",
+                std::iter::once(cmd.get_program())
+                    .chain(cmd.get_args())
+                    .map(shell_quote)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+                    .dimmed()
+            ),
+            Err(err) => println!("  {}", err.red()),
+        }
+    }
+}
+
+/// Runs the `restore` subcommand, putting originals from a backup directory back.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct for the subcommand.
+fn restore(matches: &clap::ArgMatches<'_>) {
+    let dir = Path::new(matches.value_of_os("backup-dir").expect("no backup dir"));
+    let only: Vec<PathBuf> = matches
+        .values_of_os("files")
+        .map(|files| files.map(PathBuf::from).collect())
+        .unwrap_or_default();
+    let results = backup::restore(dir, &only).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    let mut restored = 0;
+    for (entry, res) in &results {
+        match res {
+            Ok(()) => {
+                restored += 1;
+                println!("{} {}", "restored".green(), entry.original.display());
+            }
+            Err(err) => println!(
+                "{}",
+                format!("failed to restore {}: {err}", entry.original.display()).red()
+            ),
+        }
+    }
+    println!(
+        "restored {restored} of {} file{}.",
+        results.len(),
+        if results.len() == 1 { "" } else { "s" }
+    );
+}
+
+/// Runs the `purge-backups` subcommand, deleting old originals from a backup directory.
+///
+/// # Arguments
+///
+/// * `matches` - The `ArgMatches` struct for the subcommand.
+fn purge_backups(matches: &clap::ArgMatches<'_>) {
+    let dir = Path::new(matches.value_of_os("backup-dir").expect("no backup dir"));
+    let older_than =
+        backup::parse_age(matches.value_of("older-than").expect("no age")).expect("invalid age?");
+    let results = backup::purge(dir, older_than).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    let mut freed = 0;
+    for (entry, res) in &results {
+        match res {
+            Ok(size) => freed += size,
+            Err(err) => println!(
+                "{}",
+                format!(
+                    "failed to delete the backup of {}: {err}",
+                    entry.original.display()
+                )
+                .red()
+            ),
+        }
     }
-    Ok(())
+    let purged = results.iter().filter(|(_, res)| res.is_ok()).count();
+    println!(
+        "deleted {purged} original{}, freeing {}.",
+        if purged == 1 { "" } else { "s" },
+        humanize_bytes(freed)
+    );
 }
 
 /// Converts a duration in milliseconds to a human-readable string.
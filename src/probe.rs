@@ -0,0 +1,199 @@
+use crate::codec::{AudioSettings, VideoSettings};
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path, process::Command};
+
+/// What kind of data a stream carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Attachment,
+    Data,
+}
+
+/// A single stream inside a media file, as reported by ffprobe.
+#[derive(Debug, Clone)]
+pub struct Stream {
+    /// Position of the stream in the file.
+    pub index: usize,
+    pub kind: StreamKind,
+    /// ffprobe's short codec name, e.g. `h264` or `aac`.
+    pub codec: String,
+    /// Bitrate in bits per second, when the container records it per stream.
+    pub bit_rate: Option<u64>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub channels: Option<u64>,
+    pub sample_rate: Option<u64>,
+    /// Cover art and thumbnails show up as video streams, but aren't really video.
+    pub attached_pic: bool,
+}
+
+/// Everything we know about a media file before encoding it.
+#[derive(Debug, Clone)]
+pub struct MediaInfo {
+    pub streams: Vec<Stream>,
+    /// Length of the file in seconds.
+    pub duration: Option<f64>,
+    /// Overall bitrate in bits per second.
+    pub bit_rate: Option<u64>,
+    /// The tag decoreco leaves in the container, if it made this file.
+    pub tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawOutput {
+    #[serde(default)]
+    streams: Vec<RawStream>,
+    format: Option<RawFormat>,
+}
+
+#[derive(Deserialize)]
+struct RawStream {
+    index: usize,
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    bit_rate: Option<String>,
+    width: Option<u64>,
+    height: Option<u64>,
+    channels: Option<u64>,
+    sample_rate: Option<String>,
+    #[serde(default)]
+    disposition: RawDisposition,
+}
+
+#[derive(Deserialize, Default)]
+struct RawDisposition {
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+#[derive(Deserialize)]
+struct RawFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// Runs ffprobe on a file and collects its streams, duration and bitrate.
+///
+/// # Arguments
+///
+/// * `file` - The path to the file.
+///
+/// # Returns
+///
+/// * `Ok(MediaInfo)` if ffprobe could read the file.
+/// * `Err(String)` if ffprobe failed or its output couldn't be parsed, with the error message.
+pub fn probe(file: &Path) -> Result<MediaInfo, String> {
+    let res = crate::interrupt::output(
+        Command::new("ffprobe")
+            .args(["-v", "error", "-print_format", "json"])
+            .args(["-show_format", "-show_streams"])
+            .arg(file),
+    )
+    .map_err(|e| format!("failed to run ffprobe: {e}"))?;
+    if !res.status.success() {
+        return Err(format!(
+            "ffprobe failed on {}\n{}",
+            file.display(),
+            String::from_utf8_lossy(&res.stderr)
+        ));
+    }
+    let raw: RawOutput = serde_json::from_slice(&res.stdout)
+        .map_err(|e| format!("failed to parse ffprobe output: {e}"))?;
+
+    let streams = raw
+        .streams
+        .into_iter()
+        .map(|s| Stream {
+            index: s.index,
+            kind: match s.codec_type.as_deref() {
+                Some("video") => StreamKind::Video,
+                Some("audio") => StreamKind::Audio,
+                Some("subtitle") => StreamKind::Subtitle,
+                Some("attachment") => StreamKind::Attachment,
+                _ => StreamKind::Data,
+            },
+            codec: s.codec_name.unwrap_or_default(),
+            bit_rate: s.bit_rate.and_then(|b| b.parse().ok()),
+            width: s.width,
+            height: s.height,
+            channels: s.channels,
+            sample_rate: s.sample_rate.and_then(|r| r.parse().ok()),
+            attached_pic: s.disposition.attached_pic != 0,
+        })
+        .collect();
+    let format = raw.format;
+    Ok(MediaInfo {
+        streams,
+        duration: format
+            .as_ref()
+            .and_then(|f| f.duration.as_ref())
+            .and_then(|d| d.parse().ok()),
+        bit_rate: format
+            .as_ref()
+            .and_then(|f| f.bit_rate.as_ref())
+            .and_then(|b| b.parse().ok()),
+        // some muxers change the key's case
+        tag: format.and_then(|f| {
+            f.tags
+                .into_iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(crate::tag::METADATA_KEY))
+                .map(|(_, v)| v)
+        }),
+    })
+}
+
+impl MediaInfo {
+    /// The real video streams in the file, leaving out cover art.
+    pub fn video_streams(&self) -> impl Iterator<Item = &Stream> {
+        self.streams
+            .iter()
+            .filter(|s| s.kind == StreamKind::Video && !s.attached_pic)
+    }
+
+    /// The resolution of the first video stream, if there is one.
+    pub fn resolution(&self) -> Option<(u64, u64)> {
+        let video = self.video_streams().next()?;
+        Some((video.width?, video.height?))
+    }
+
+    /// Checks whether every video and audio stream is already how we'd encode it,
+    /// in which case re-encoding would only lose quality.
+    ///
+    /// # Arguments
+    ///
+    /// * `video` - The video settings we're encoding with.
+    /// * `audio` - The audio settings we're encoding with.
+    pub fn matches(&self, video: &VideoSettings, audio: &AudioSettings) -> bool {
+        self.streams
+            .iter()
+            .all(|s| !video.needs_encode(s) && !audio.needs_encode(s))
+    }
+}
+
+/// Checks that an encoded file kept every stream of the original, in the same order.
+///
+/// # Arguments
+///
+/// * `original` - What ffprobe found in the original file.
+/// * `encoded` - What ffprobe found in the encoded file.
+///
+/// # Returns
+///
+/// * `Ok(())` if the stream counts and types line up.
+/// * `Err(String)` describing the difference otherwise.
+pub fn compare_streams(original: &MediaInfo, encoded: &MediaInfo) -> Result<(), String> {
+    let kinds = |info: &MediaInfo| info.streams.iter().map(|s| s.kind).collect::<Vec<_>>();
+    let (before, after) = (kinds(original), kinds(encoded));
+    if before == after {
+        Ok(())
+    } else {
+        Err(format!(
+            "output has different streams than the input: {before:?} became {after:?}"
+        ))
+    }
+}
@@ -0,0 +1,210 @@
+use crate::events::{self, Event, Sink};
+use colored::Colorize;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// The progress bars for a run: one for the whole run, and one for each file being worked on.
+///
+/// The overall bar is measured in whatever each file's weight is, which is its duration
+/// in milliseconds for videos, so its ETA is based on how much media is left rather
+/// than how many files.
+///
+/// With `--output json` the bars are hidden, and files starting and moving along are
+/// written as events instead. Without a terminal to draw them on, they're hidden too, and
+/// what happened to each file is printed as a line of its own.
+pub struct Progress<'a> {
+    sink: &'a Sink,
+    /// Whether the bars are being drawn.
+    bars: bool,
+    multi: MultiProgress,
+    /// The bar for the whole run.
+    pub overall: ProgressBar,
+    files: usize,
+    done: AtomicUsize,
+}
+
+/// A file that's being worked on, with its own bar. When it's dropped, the file counts
+/// as done and whatever's left of its weight goes on the overall bar.
+pub struct Job<'a> {
+    progress: &'a Progress<'a>,
+    bar: ProgressBar,
+    file: PathBuf,
+    weight: u64,
+    credited: AtomicU64,
+    /// The last speed ffmpeg gave, for progress events.
+    speed: Mutex<Option<String>>,
+}
+
+impl<'a> Progress<'a> {
+    /// Sets up the overall bar.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - How many files there are.
+    /// * `sink` - Where events go, which decides whether the bars are shown.
+    pub fn new(files: usize, sink: &'a Sink) -> Self {
+        // indicatif draws on stderr
+        let bars = !sink.json() && std::io::stderr().is_terminal();
+        let multi = if bars {
+            MultiProgress::new()
+        } else {
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+        };
+        let overall = multi.add(ProgressBar::new(0));
+        overall.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {prefix:>9} {percent:>3}% eta {eta:<4} {msg}",
+                )
+                .expect("failed to set progress bar template")
+                .progress_chars("##-"),
+        );
+        overall.set_prefix(format!("0/{files}"));
+        if bars {
+            overall.enable_steady_tick(Duration::from_millis(100));
+        }
+        Self {
+            sink,
+            bars,
+            multi,
+            overall,
+            files,
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts a file's bar.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file.
+    /// * `weight` - How much of the overall bar it makes up.
+    pub fn start(&'a self, file: &Path, weight: u64) -> Job<'a> {
+        let bar = self.multi.add(ProgressBar::new(weight.max(1)));
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("  {bar:30.green/white} {percent:>3}% {prefix:>7} {wide_msg}")
+                .expect("failed to set progress bar template")
+                .progress_chars("=> "),
+        );
+        bar.set_message(crate::truncate(&file.display().to_string(), 60));
+        self.sink.emit(&Event::JobStarted {
+            path: events::path(file),
+        });
+        Job {
+            progress: self,
+            bar,
+            file: file.to_path_buf(),
+            weight,
+            credited: AtomicU64::new(0),
+            speed: Mutex::new(None),
+        }
+    }
+
+    /// Shows what just happened to a file, next to the overall bar, or as a line of text
+    /// if there are no bars.
+    pub fn message(&self, message: String) {
+        if self.bars {
+            self.overall.set_message(message);
+        } else {
+            self.sink.say(message);
+        }
+    }
+
+    /// Prints a line above the bars, or just prints it if there are none.
+    pub fn println(&self, line: impl Display) {
+        if self.bars {
+            self.overall.println(line.to_string());
+        } else {
+            self.sink.say(line);
+        }
+    }
+
+    /// Prints a warning in yellow above the bars, or writes it as a `warning` event.
+    pub fn warn(&self, message: &str) {
+        if self.sink.json() {
+            self.sink.warn(message);
+        } else {
+            self.println(message.yellow());
+        }
+    }
+
+    /// Removes every bar, once the run is over.
+    pub fn finish(&self) {
+        self.overall.finish_and_clear();
+        let _ = self.multi.clear();
+    }
+}
+
+impl Job<'_> {
+    /// Moves the file's bar along.
+    ///
+    /// # Arguments
+    ///
+    /// * `done` - How much of its weight is done, e.g. milliseconds encoded.
+    pub fn update(&self, done: u64) {
+        let done = done.min(self.weight);
+        self.bar.set_position(done);
+        let before = self.credited.fetch_max(done, Ordering::SeqCst);
+        if done > before {
+            self.progress.overall.inc(done - before);
+        }
+    }
+
+    /// Reads a line of ffmpeg's `-progress` output and moves the bar along to match.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - A `key=value` line, of which only the time encoded so far and the speed
+    ///   are used, and the `progress` line that ends each update.
+    pub fn ffmpeg(&self, line: &str) {
+        match line.split_once('=') {
+            // despite the name, out_time_ms is in microseconds too
+            Some(("out_time_us" | "out_time_ms", us)) => {
+                if let Ok(us) = us.parse::<u64>() {
+                    self.update(us / 1000);
+                }
+            }
+            Some(("speed", speed)) if speed.trim() != "N/A" => {
+                self.status(speed.trim());
+                *self.speed.lock().expect("poisoned") = Some(speed.trim().to_string());
+            }
+            Some(("progress", _)) => self.progress.sink.emit(&Event::Progress {
+                path: events::path(&self.file),
+                done: self.credited.load(Ordering::SeqCst),
+                total: self.weight,
+                speed: self.speed.lock().expect("poisoned").clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    /// Shows what's happening to the file, like the encoder's speed or that it's being verified.
+    pub fn status(&self, status: &str) {
+        self.bar.set_prefix(status.to_string());
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        let credited = self.credited.load(Ordering::SeqCst);
+        self.progress
+            .overall
+            .inc(self.weight.saturating_sub(credited));
+        let done = self.progress.done.fetch_add(1, Ordering::SeqCst) + 1;
+        self.progress
+            .overall
+            .set_prefix(format!("{done}/{}", self.progress.files));
+        self.bar.finish_and_clear();
+        self.progress.multi.remove(&self.bar);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{codec::VideoSettings, diagnose::Failure, error::Error};
+use std::{fmt, path::Path, process::Command};
+
+/// How many seconds each sampled segment lasts.
+const SAMPLE_LENGTH: f64 = 4.0;
+
+/// A perceptual metric that ffmpeg can measure between an encode and its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Ssim,
+    Psnr,
+    Vmaf,
+}
+
+impl Metric {
+    /// Reads the metric from its command line name.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "ssim" => Self::Ssim,
+            "psnr" => Self::Psnr,
+            "vmaf" => Self::Vmaf,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The lavfi filter that compares two videos, the encode first and the source second.
+    fn filter(self) -> &'static str {
+        match self {
+            Self::Ssim => "ssim",
+            Self::Psnr => "psnr",
+            Self::Vmaf => "libvmaf",
+        }
+    }
+
+    /// Pulls the overall score out of the filter's log line.
+    ///
+    /// # Arguments
+    ///
+    /// * `stderr` - What ffmpeg printed while running the filter.
+    fn parse(self, stderr: &str) -> Option<f64> {
+        let (line, key) = match self {
+            Self::Ssim => ("SSIM ", "All:"),
+            Self::Psnr => ("PSNR ", "average:"),
+            Self::Vmaf => ("VMAF score", "VMAF score:"),
+        };
+        let line = stderr.lines().rev().find(|l| l.contains(line))?;
+        let value = line[line.find(key)? + key.len()..]
+            .split_whitespace()
+            .next()?;
+        // identical frames give a psnr of inf, which is as good as it gets
+        if value == "inf" {
+            Some(f64::INFINITY)
+        } else {
+            value.parse().ok()
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ssim => "ssim",
+            Self::Psnr => "psnr",
+            Self::Vmaf => "vmaf",
+        })
+    }
+}
+
+/// Checks whether the local ffmpeg was built with libvmaf.
+pub fn has_vmaf() -> bool {
+    Command::new("ffmpeg")
+        .args(["-hide_banner", "-filters"])
+        .output()
+        .is_ok_and(|o| String::from_utf8_lossy(&o.stdout).contains("libvmaf"))
+}
+
+/// The crf a quality search settled on, and the score it measured there.
+#[derive(Debug, Clone, Copy)]
+pub struct Choice {
+    pub crf: u8,
+    pub score: f64,
+}
+
+/// Searches for the highest crf whose encode still meets a quality target.
+///
+/// A few short segments spread through the video are encoded at each candidate
+/// crf and compared against the source, and a binary search narrows the crf
+/// down, assuming quality only drops as the crf goes up.
+///
+/// # Arguments
+///
+/// * `file` - The path to the file.
+/// * `stream` - The index of the video stream to sample.
+/// * `duration` - The length of the file in seconds, if known.
+/// * `video` - The encoder settings to search with. The crf is ignored.
+/// * `metric` - The metric to measure quality with.
+/// * `target` - The lowest acceptable score.
+/// * `samples` - How many segments to sample.
+/// * `prefix` - Where to put the sample encodes, their extension gets replaced.
+///
+/// # Returns
+///
+/// * `Ok(Choice)` with the crf to use. If even the best crf misses the target,
+///   that's what's returned, along with the score it got.
+/// * `Err(Error::Encode)` if ffmpeg couldn't be run or didn't give a score.
+/// * `Err(Error::Encoder)` if ffmpeg failed, with what probably went wrong.
+#[allow(clippy::too_many_arguments)]
+pub fn search(
+    file: &Path,
+    stream: usize,
+    duration: Option<f64>,
+    video: &VideoSettings,
+    metric: Metric,
+    target: f64,
+    samples: usize,
+    prefix: &Path,
+) -> Result<Choice, Error> {
+    // spread the samples evenly, or just use the whole thing if it's too short to split up
+    let segments: Vec<(f64, Option<f64>)> = match duration {
+        Some(d) if d > SAMPLE_LENGTH * samples as f64 => (1..=samples)
+            .map(|k| (d * k as f64 / (samples + 1) as f64, Some(SAMPLE_LENGTH)))
+            .collect(),
+        _ => vec![(0.0, None)],
+    };
+
+    let (mut low, mut high) = video.crf_range();
+    let mut best: Option<Choice> = None;
+    let mut closest: Option<Choice> = None;
+    while low <= high {
+        let crf = low + (high - low) / 2;
+        let score = measure(file, stream, &segments, video, crf, metric, prefix)?;
+        let choice = Choice { crf, score };
+        if score >= target {
+            best = Some(choice);
+            low = crf + 1;
+        } else {
+            if closest.is_none_or(|c| crf < c.crf) {
+                closest = Some(choice);
+            }
+            if crf == 0 {
+                break;
+            }
+            high = crf - 1;
+        }
+    }
+    best.or(closest).ok_or_else(|| {
+        Error::Encode(format!(
+            "quality search on {} found nothing",
+            file.display()
+        ))
+    })
+}
+
+/// Encodes every segment at one crf and averages their scores.
+fn measure(
+    file: &Path,
+    stream: usize,
+    segments: &[(f64, Option<f64>)],
+    video: &VideoSettings,
+    crf: u8,
+    metric: Metric,
+    prefix: &Path,
+) -> Result<f64, Error> {
+    let video = VideoSettings {
+        crf,
+        ..video.clone()
+    };
+    let mut total = 0.0;
+    for (k, (start, length)) in segments.iter().enumerate() {
+        let sample = prefix.with_extension(format!("sample{k}.mkv"));
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-v", "error", "-y"]);
+        seek(&mut cmd, *start, *length);
+        let res = crate::interrupt::output(
+            cmd.arg("-i")
+                .arg(file)
+                .args(["-map", &format!("0:{stream}")])
+                .args(video.args(0))
+                .arg("-threads")
+                .arg(video.threads.to_string())
+                .arg(&sample),
+        )
+        .map_err(|e| Error::Encode(format!("failed to run ffmpeg: {e}")))?;
+        if !res.status.success() {
+            return Err(Error::Encoder(Box::new(Failure::new(
+                "encode",
+                "encode a sample of",
+                file,
+                String::from_utf8_lossy(&res.stderr).into_owned(),
+            ))));
+        }
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.args(["-hide_banner", "-nostats"])
+            .arg("-i")
+            .arg(&sample);
+        seek(&mut cmd, *start, *length);
+        let res = crate::interrupt::output(
+            cmd.arg("-i")
+                .arg(file)
+                .args(["-lavfi", &format!("[0:v:0][1:{stream}]{}", metric.filter())])
+                .args(["-f", "null", "-"]),
+        )
+        .map_err(|e| Error::Encode(format!("failed to run ffmpeg: {e}")))?;
+        let _ = std::fs::remove_file(&sample);
+        let stderr = String::from_utf8_lossy(&res.stderr).into_owned();
+        if !res.status.success() {
+            return Err(Error::Encoder(Box::new(Failure::new(
+                "encode",
+                &format!("measure the {metric} of"),
+                file,
+                stderr,
+            ))));
+        }
+        total += metric.parse(&stderr).ok_or_else(|| {
+            Error::Encode(format!(
+                "couldn't find the {metric} score in ffmpeg's output"
+            ))
+        })?;
+    }
+    Ok(total / segments.len() as f64)
+}
+
+/// Adds input seeking for a segment to an ffmpeg command.
+fn seek(cmd: &mut Command, start: f64, length: Option<f64>) {
+    if let Some(length) = length {
+        cmd.arg("-ss").arg(format!("{start:.3}"));
+        cmd.arg("-t").arg(format!("{length:.3}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ssim() {
+        let stderr = "\
+Input #0, matroska,webm, from 'sample0.mkv':
+[Parsed_ssim_0 @ 0x600003a8c000] SSIM Y:0.985117 (18.274137) U:0.990838 (20.380154) V:0.990295 (20.130297) All:0.987125 (18.902005)
+";
+        assert_eq!(Metric::Ssim.parse(stderr), Some(0.987125));
+        // identical frames give an ssim of 1 and a db value of inf
+        let stderr = "[Parsed_ssim_0 @ 0x5581] SSIM Y:1.000000 (inf) U:1.000000 (inf) V:1.000000 (inf) All:1.000000 (inf)";
+        assert_eq!(Metric::Ssim.parse(stderr), Some(1.0));
+    }
+
+    #[test]
+    fn parses_psnr() {
+        let stderr = "[Parsed_psnr_0 @ 0x55d1c2a0] PSNR y:42.524818 u:46.943617 v:47.216584 average:43.742413 min:40.897446 max:46.035211";
+        assert_eq!(Metric::Psnr.parse(stderr), Some(43.742413));
+        let stderr =
+            "[Parsed_psnr_0 @ 0x55d1c2a0] PSNR y:inf u:inf v:inf average:inf min:inf max:inf";
+        assert_eq!(Metric::Psnr.parse(stderr), Some(f64::INFINITY));
+    }
+
+    #[test]
+    fn parses_vmaf() {
+        // older ffmpegs name the filter libvmaf, newer ones Parsed_libvmaf_0
+        let stderr = "[libvmaf @ 0x5616b6e0c6c0] VMAF score: 95.123456";
+        assert_eq!(Metric::Vmaf.parse(stderr), Some(95.123456));
+        let stderr = "\
+frame=  100 fps= 25 q=-0.0 Lsize=N/A time=00:00:04.00 bitrate=N/A speed=0.99x
+[Parsed_libvmaf_0 @ 0x7f8e4c004a80] VMAF score: 97.428043
+";
+        assert_eq!(Metric::Vmaf.parse(stderr), Some(97.428043));
+    }
+
+    #[test]
+    fn uses_the_last_score() {
+        let stderr = "\
+[Parsed_psnr_0 @ 0x1] PSNR y:30.0 u:30.0 v:30.0 average:30.000000 min:29.0 max:31.0
+[Parsed_psnr_0 @ 0x1] PSNR y:40.0 u:40.0 v:40.0 average:40.000000 min:39.0 max:41.0
+";
+        assert_eq!(Metric::Psnr.parse(stderr), Some(40.0));
+    }
+
+    #[test]
+    fn finds_nothing_without_a_score() {
+        let stderr = "[Parsed_ssim_0 @ 0x1] Input stream is empty\nConversion failed!";
+        assert_eq!(Metric::Ssim.parse(stderr), None);
+        assert_eq!(Metric::Psnr.parse(""), None);
+        // the wrong metric's line doesn't count
+        let stderr =
+            "[Parsed_ssim_0 @ 0x1] SSIM Y:0.98 (17.0) U:0.99 (20.0) V:0.99 (20.0) All:0.98 (18.0)";
+        assert_eq!(Metric::Vmaf.parse(stderr), None);
+        assert_eq!(Metric::Psnr.parse(stderr), None);
+    }
+}
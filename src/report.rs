@@ -0,0 +1,230 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// What happened to a file, as far as the report and the journal are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The encode was smaller and took the original's place.
+    Replaced,
+    /// The encode was smaller, but it was a trial run so the original was left alone.
+    Encoded,
+    /// The encode was bigger, so the original was kept.
+    Larger,
+    /// It was already in the target codecs, or empty.
+    Skipped,
+    /// decoreco already encoded it in an earlier run.
+    Tagged,
+    /// An earlier run already dealt with it, and `--resume` was given.
+    Resumed,
+    /// The encode didn't pass verification.
+    Rejected,
+    /// Something went wrong, see the error.
+    Failed,
+    /// ctrl-c stopped it, or stopped it from starting.
+    Interrupted,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Replaced => "replaced",
+            Self::Encoded => "encoded",
+            Self::Larger => "larger",
+            Self::Skipped => "skipped",
+            Self::Tagged => "tagged",
+            Self::Resumed => "resumed",
+            Self::Rejected => "rejected",
+            Self::Failed => "failed",
+            Self::Interrupted => "interrupted",
+        })
+    }
+}
+
+/// Everything we know about what happened to one file in a run.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    /// The path to the file, as it was found.
+    #[serde(serialize_with = "lossy")]
+    pub path: PathBuf,
+    pub action: Action,
+    /// The size before and after encoding, if it got that far.
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    /// The codecs of the video and audio streams ffprobe found, in stream order.
+    pub codecs: Vec<String>,
+    /// How long it is in seconds, if ffprobe knows.
+    pub duration: Option<f64>,
+    /// How long encoding it took in seconds, including any crf search.
+    pub encode_time: Option<f64>,
+    /// The exit code of the encoder, if it got run and wasn't killed by a signal.
+    pub exit_status: Option<i32>,
+    /// Which step went wrong, e.g. `probe` or `encode`, and what went wrong, for files
+    /// that failed or were rejected.
+    pub error_kind: Option<&'static str>,
+    pub error: Option<String>,
+}
+
+impl Entry {
+    /// Starts an entry for a file, which fails unless something says otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file.
+    pub fn new(file: &Path) -> Self {
+        Self {
+            path: file.to_path_buf(),
+            action: Action::Failed,
+            old_size: None,
+            new_size: None,
+            codecs: Vec::new(),
+            duration: None,
+            encode_time: None,
+            exit_status: None,
+            error_kind: None,
+            error: None,
+        }
+    }
+
+    /// Fills in what went wrong with the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `err` - The error.
+    pub fn set_error(&mut self, err: &Error) {
+        self.error_kind = Some(err.kind());
+        self.error = Some(err.message().to_string());
+    }
+
+    /// Starts an entry for a file that's already been dealt with.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file.
+    /// * `action` - What happened to it.
+    pub fn with_action(file: &Path, action: Action) -> Self {
+        Self {
+            action,
+            ..Self::new(file)
+        }
+    }
+}
+
+/// Serializes a path as a string, even if it isn't valid unicode.
+fn lossy<S: serde::Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+/// Writes the report for a run.
+///
+/// # Arguments
+///
+/// * `path` - Where to write it.
+/// * `format` - Either `json`, for an array of objects, or `csv`, with a header row.
+/// * `entries` - One entry per file, in the order they were found.
+///
+/// # Returns
+///
+/// * `Ok(())` once it's written.
+/// * `Err(String)` if it couldn't be, with the error message.
+pub fn write(path: &Path, format: &str, entries: &[Entry]) -> Result<(), String> {
+    let err = |e: io::Error| format!("failed to write report to {}: {e}", path.display());
+    let mut out = BufWriter::new(File::create(path).map_err(err)?);
+    match format {
+        "csv" => write_csv(&mut out, entries).map_err(err)?,
+        _ => {
+            serde_json::to_writer_pretty(&mut out, entries).map_err(|e| err(e.into()))?;
+            out.write_all(b"\n").map_err(err)?;
+        }
+    }
+    out.flush().map_err(err)
+}
+
+/// Writes the entries as csv, with the codecs separated by spaces.
+fn write_csv(out: &mut impl Write, entries: &[Entry]) -> io::Result<()> {
+    writeln!(
+        out,
+        "path,action,old_size,new_size,codecs,duration,encode_time,exit_status,error_kind,error"
+    )?;
+    for entry in entries {
+        let fields = [
+            entry.path.to_string_lossy().into_owned(),
+            entry.action.to_string(),
+            opt(entry.old_size),
+            opt(entry.new_size),
+            entry.codecs.join(" "),
+            opt(entry.duration),
+            opt(entry.encode_time),
+            opt(entry.exit_status),
+            opt(entry.error_kind),
+            opt(entry.error.as_deref().map(str::trim)),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| quote(f)).collect();
+        writeln!(out, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+/// Formats a csv field, leaving it blank if we don't know it.
+fn opt<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Quotes a csv field if it needs it, doubling any quotes inside.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        assert_eq!(quote("a.mkv"), "a.mkv");
+        assert_eq!(quote(""), "");
+        assert_eq!(quote("a, b.mkv"), "\"a, b.mkv\"");
+        assert_eq!(quote("say \"hi\".mkv"), "\"say \"\"hi\"\".mkv\"");
+        assert_eq!(quote("two\nlines.mkv"), "\"two\nlines.mkv\"");
+        assert_eq!(quote("cr\r.mkv"), "\"cr\r.mkv\"");
+    }
+
+    #[test]
+    fn writes_csv() {
+        let mut replaced = Entry::with_action(Path::new("videos/a, b.mkv"), Action::Replaced);
+        replaced.old_size = Some(2000);
+        replaced.new_size = Some(1000);
+        replaced.codecs = vec!["h264".to_string(), "aac".to_string()];
+        replaced.duration = Some(12.5);
+        replaced.encode_time = Some(3.25);
+        replaced.exit_status = Some(0);
+        let mut failed = Entry::new(Path::new("c.mp4"));
+        failed.exit_status = Some(1);
+        failed.set_error(&Error::Encode(
+            "failed to encode: ffmpeg said \"no\"\n".to_string(),
+        ));
+        let skipped = Entry::with_action(Path::new("d.webm"), Action::Skipped);
+
+        let mut out = Vec::new();
+        write_csv(&mut out, &[replaced, failed, skipped]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\
+path,action,old_size,new_size,codecs,duration,encode_time,exit_status,error_kind,error
+\"videos/a, b.mkv\",replaced,2000,1000,h264 aac,12.5,3.25,0,,
+c.mp4,failed,,,,,,1,encode,\"failed to encode: ffmpeg said \"\"no\"\"\"
+d.webm,skipped,,,,,,,,
+"
+        );
+    }
+}
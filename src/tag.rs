@@ -0,0 +1,48 @@
+use std::{io, path::Path};
+
+/// The container metadata key ffmpeg writes into every video decoreco encodes.
+pub const METADATA_KEY: &str = "DECORECO";
+/// The extended attribute set on every file decoreco replaces, which is much cheaper to
+/// check than the container tag but doesn't survive being copied everywhere.
+pub const XATTR: &str = "user.decoreco";
+
+/// Builds the tag for an encode, e.g. `hevc,28,0.2.3`.
+///
+/// # Arguments
+///
+/// * `codec` - The codec it was encoded to.
+/// * `quality` - The crf, or whatever stands in for it.
+pub fn value(codec: &str, quality: &str) -> String {
+    format!("{codec},{quality},{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Reads the tag off a file's extended attributes, if it has one.
+#[cfg(unix)]
+pub fn read(file: &Path) -> Option<String> {
+    xattr::get(file, XATTR)
+        .ok()
+        .flatten()
+        .map(|v| String::from_utf8_lossy(&v).into_owned())
+}
+
+/// Sets the tag on a file's extended attributes.
+///
+/// # Arguments
+///
+/// * `file` - The path to the file.
+/// * `value` - The tag, from `value`.
+#[cfg(unix)]
+pub fn mark(file: &Path, value: &str) -> io::Result<()> {
+    xattr::set(file, XATTR, value.as_bytes())
+}
+
+/// Without xattrs, only the container tag is there to go by.
+#[cfg(not(unix))]
+pub fn read(_file: &Path) -> Option<String> {
+    None
+}
+
+#[cfg(not(unix))]
+pub fn mark(_file: &Path, _value: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use crate::{commit, workspace::device};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// An original that's been put in the trash, but is still at its old path too.
+///
+/// The trash gets a hard link (or a copy, if it has to cross filesystems) so the original
+/// stays where it is until the encode has safely replaced it.
+pub struct Trashed {
+    file: PathBuf,
+    info: PathBuf,
+}
+
+impl Trashed {
+    /// Takes the original back out of the trash, if it didn't end up being replaced.
+    pub fn discard(self) -> io::Result<()> {
+        fs::remove_file(self.file)?;
+        fs::remove_file(self.info)
+    }
+}
+
+/// Puts a copy of a file in the trash, following the freedesktop.org trash spec, so it
+/// shows up in file managers and can be restored from there.
+///
+/// Files on the same filesystem as the home directory go to `$XDG_DATA_HOME/Trash`.
+/// Files on other mounts go to `$topdir/.Trash/$uid` if the admin set one up, or
+/// `$topdir/.Trash-$uid` otherwise, and fall back to the home trash if neither works.
+///
+/// # Arguments
+///
+/// * `file` - The path to the file that's about to be replaced or deleted.
+///
+/// # Returns
+///
+/// * `Ok(Trashed)` once the file is in the trash.
+/// * `Err(io::Error)` if it couldn't be put there.
+pub fn stash(file: &Path) -> io::Result<Trashed> {
+    let file = std::path::absolute(file)?;
+    let home = home_trash()?;
+    let file_device = device(&file)?;
+    if file_device == device(&home)? {
+        return stash_in(&home, &file, &file);
+    }
+    let top = mount_point(&file, file_device)?;
+    for trash in [admin_trash(&top), user_trash(&top)].into_iter().flatten() {
+        // paths in a trash on another mount are relative to its top directory
+        let relative = file.strip_prefix(&top).map_err(io::Error::other)?;
+        if let Ok(trashed) = stash_in(&trash, &file, relative) {
+            return Ok(trashed);
+        }
+    }
+    stash_in(&home, &file, &file)
+}
+
+/// Puts a file in a particular trash directory.
+///
+/// The info file is created first, exclusively, which is how the spec reserves a name.
+///
+/// # Arguments
+///
+/// * `trash` - The trash directory, with `files` and `info` inside it.
+/// * `file` - The absolute path to the file.
+/// * `path` - What to record as the original path in the info file.
+fn stash_in(trash: &Path, file: &Path, path: &Path) -> io::Result<Trashed> {
+    let files = trash.join("files");
+    let infos = trash.join("info");
+    fs::create_dir_all(&files)?;
+    fs::create_dir_all(&infos)?;
+    let name = file
+        .file_name()
+        .ok_or_else(|| io::Error::other("can't trash a path without a file name"))?;
+    let date = chrono::Local::now().format("%Y-%m-%dT%H:%M:%S");
+    let mut n = 1;
+    loop {
+        // a.mp4, then a.2.mp4, a.3.mp4 and so on
+        let name = if n == 1 {
+            name.to_os_string()
+        } else {
+            let stem = Path::new(name).file_stem().unwrap_or(name);
+            let mut numbered = stem.to_os_string();
+            numbered.push(format!(".{n}"));
+            if let Some(ext) = Path::new(name).extension() {
+                numbered.push(".");
+                numbered.push(ext);
+            }
+            numbered
+        };
+        let mut info_name = name.clone();
+        info_name.push(".trashinfo");
+        let info = infos.join(info_name);
+        let mut info_file = match File::options().write(true).create_new(true).open(&info) {
+            Ok(info_file) => info_file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let trashed = files.join(&name);
+        let written = write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={date}\n",
+            encode_path(path)
+        )
+        .and_then(|()| info_file.sync_all())
+        .and_then(|()| commit::link_or_copy(file, &trashed));
+        return match written {
+            Ok(()) => Ok(Trashed {
+                file: trashed,
+                info,
+            }),
+            Err(e) => {
+                let _ = fs::remove_file(&info);
+                Err(e)
+            }
+        };
+    }
+}
+
+/// The user's own trash, `$XDG_DATA_HOME/Trash`, created if it doesn't exist.
+fn home_trash() -> io::Result<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .filter(|d| Path::new(d).is_absolute())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".local/share")))
+        .ok_or_else(|| io::Error::other("neither XDG_DATA_HOME nor HOME are set"))?;
+    let trash = data.join("Trash");
+    fs::create_dir_all(&trash)?;
+    Ok(trash)
+}
+
+/// Finds the top directory of the mount a file is on, by walking up until the device changes.
+fn mount_point(file: &Path, device_id: u64) -> io::Result<PathBuf> {
+    let mut top = file.parent().unwrap_or(file);
+    while let Some(parent) = top.parent() {
+        if device(parent)? != device_id {
+            break;
+        }
+        top = parent;
+    }
+    Ok(top.to_path_buf())
+}
+
+/// `$topdir/.Trash/$uid`, which can only be used if the admin made `.Trash` a real
+/// directory with the sticky bit set.
+#[cfg(unix)]
+fn admin_trash(top: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+    let shared = top.join(".Trash");
+    let metadata = fs::symlink_metadata(&shared).ok()?;
+    if !metadata.is_dir() || metadata.permissions().mode() & 0o1000 == 0 {
+        return None;
+    }
+    let trash = shared.join(uid().ok()?.to_string());
+    fs::create_dir_all(&trash).ok()?;
+    Some(trash)
+}
+
+/// `$topdir/.Trash-$uid`, made private to the user if it has to be created.
+#[cfg(unix)]
+fn user_trash(top: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    let uid = uid().ok()?;
+    let trash = top.join(format!(".Trash-{uid}"));
+    match fs::DirBuilder::new().mode(0o700).create(&trash) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(_) => return None,
+    }
+    // someone else's directory, or a symlink to one, isn't ours to use
+    let metadata = fs::symlink_metadata(&trash).ok()?;
+    (metadata.is_dir() && metadata.uid() == uid).then_some(trash)
+}
+
+/// The effective user id, read off a file we just made.
+#[cfg(unix)]
+fn uid() -> io::Result<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(tempfile::tempfile()?.metadata()?.uid())
+}
+
+/// Other platforms have their own trash, so everything goes in the home one.
+#[cfg(not(unix))]
+fn admin_trash(_top: &Path) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(not(unix))]
+fn user_trash(_top: &Path) -> Option<PathBuf> {
+    None
+}
+
+/// Percent-encodes a path for the info file, keeping the slashes.
+fn encode_path(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec();
+    #[cfg(not(unix))]
+    let bytes = path.to_string_lossy().into_owned().into_bytes();
+    bytes
+        .into_iter()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
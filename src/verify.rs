@@ -0,0 +1,122 @@
+use crate::{
+    diagnose::Failure,
+    error::Error,
+    probe::{self, MediaInfo},
+};
+use std::{path::Path, process::Command};
+
+/// How far apart the durations can be, in seconds, before an encode is rejected.
+/// Longer files get a little more leeway on top of this.
+const DURATION_TOLERANCE: f64 = 1.0;
+
+/// Checks that an encoded video is complete before it's allowed to replace the original.
+///
+/// The output has to have the same streams as the input, about the same duration,
+/// and has to decode all the way through without errors.
+///
+/// # Arguments
+///
+/// * `file` - The path to the original file.
+/// * `original` - What ffprobe found in the original file.
+/// * `encoded` - The path to the encoded file.
+/// * `decode` - Whether to do the full decode pass, which takes a while on big files.
+/// * `threads` - How many threads the decoder gets.
+///
+/// # Returns
+///
+/// * `Ok(())` if the encode looks good.
+/// * `Err(Error::Verify)` with the reason it was rejected.
+/// * `Err(Error::Encoder)` if it didn't decode cleanly, with what probably went wrong.
+pub fn verify(
+    file: &Path,
+    original: &MediaInfo,
+    encoded: &Path,
+    decode: bool,
+    threads: usize,
+) -> Result<(), Error> {
+    let info = probe::probe(encoded).map_err(Error::Verify)?;
+    probe::compare_streams(original, &info).map_err(Error::Verify)?;
+
+    if let Some(before) = original.duration {
+        let after = info
+            .duration
+            .ok_or_else(|| Error::Verify("output has no duration".to_string()))?;
+        let tolerance = DURATION_TOLERANCE.max(before * 0.01);
+        if (before - after).abs() > tolerance {
+            return Err(Error::Verify(format!(
+                "output is {after:.1}s long but the input is {before:.1}s"
+            )));
+        }
+    }
+
+    if decode {
+        let res = crate::interrupt::output(
+            Command::new("ffmpeg")
+                .args(["-v", "error", "-xerror"])
+                .arg("-threads")
+                .arg(threads.to_string())
+                .arg("-i")
+                .arg(encoded)
+                // only audio and video are worth decoding, attachments can't go to the null muxer
+                .args(["-map", "0:v?", "-map", "0:a?", "-f", "null", "-"]),
+        )
+        .map_err(|e| Error::Verify(format!("failed to run ffmpeg: {e}")))?;
+        if !res.status.success() || !res.stderr.is_empty() {
+            return Err(Error::Encoder(Box::new(Failure::new(
+                "verify",
+                "decode the encode of",
+                file,
+                String::from_utf8_lossy(&res.stderr).into_owned(),
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a jxl made from an image is complete before it's allowed to replace the
+/// original, which gets deleted once it has.
+///
+/// The output can't be empty, and has to decode with djxl without errors.
+///
+/// # Arguments
+///
+/// * `file` - The path to the original image.
+/// * `encoded` - The path to the encoded jxl.
+/// * `decode` - Whether to decode it, which needs djxl.
+///
+/// # Returns
+///
+/// * `Ok(())` if the encode looks good.
+/// * `Err(Error::Verify)` with the reason it was rejected.
+/// * `Err(Error::Encoder)` if it didn't decode cleanly, with what probably went wrong.
+pub fn verify_image(file: &Path, encoded: &Path, decode: bool) -> Result<(), Error> {
+    let size = std::fs::metadata(encoded)
+        .map_err(|e| Error::Verify(format!("failed to read output: {e}")))?
+        .len();
+    if size == 0 {
+        return Err(Error::Verify("output is empty".to_string()));
+    }
+
+    if decode {
+        let res = crate::interrupt::output(
+            Command::new("djxl")
+                .arg(encoded)
+                .arg("--disable_output")
+                .arg("--quiet"),
+        )
+        .map_err(|e| {
+            Error::Verify(format!(
+                "failed to run djxl: {e}. it comes with cjxl, or use --no-decode-check to skip this"
+            ))
+        })?;
+        if !res.status.success() {
+            return Err(Error::Encoder(Box::new(Failure::new(
+                "verify",
+                "decode the encode of",
+                file,
+                String::from_utf8_lossy(&res.stderr).into_owned(),
+            ))));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,216 @@
+use crate::discover;
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+use tempfile::{Builder, TempDir};
+
+/// Where encodes are written while they're in progress, and how much space they're using.
+///
+/// By default each file gets encoded into a hidden directory next to it, so it's on the
+/// same filesystem and replacing the original is a cheap rename. With `--tmp-dir`, all
+/// encodes share one directory inside the given path instead.
+pub struct Workspace {
+    /// The shared directory, if `--tmp-dir` was given.
+    shared: Option<TempDir>,
+    /// Hidden directories made next to the files, keyed by the directory they're in.
+    hidden: Mutex<HashMap<PathBuf, TempDir>>,
+    /// Bytes promised to running jobs, keyed by the filesystem they're on.
+    reserved: Mutex<HashMap<u64, u64>>,
+    /// Signalled whenever a job gives its space back.
+    freed: Condvar,
+}
+
+/// Space held for a running job, given back when it's dropped.
+pub struct Reservation<'a> {
+    workspace: &'a Workspace,
+    device: u64,
+    bytes: u64,
+}
+
+impl Workspace {
+    /// Sets up a workspace.
+    ///
+    /// # Arguments
+    ///
+    /// * `tmp_dir` - A directory to put every encode in, or `None` to use hidden
+    ///   directories next to each file.
+    pub fn new(tmp_dir: Option<&Path>) -> io::Result<Self> {
+        let shared = tmp_dir
+            .map(|dir| Builder::new().prefix("decoreco").tempdir_in(dir))
+            .transpose()?;
+        if let Some(shared) = &shared {
+            crate::interrupt::remove_on_exit(shared.path().to_path_buf());
+        }
+        Ok(Self {
+            shared,
+            hidden: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(HashMap::new()),
+            freed: Condvar::new(),
+        })
+    }
+
+    /// Gets the directory a file would be encoded into, or the one it'll be made in.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file.
+    fn location<'a>(&'a self, file: &'a Path) -> &'a Path {
+        match (&self.shared, file.parent()) {
+            (Some(shared), _) => shared.path(),
+            (None, Some(dir)) if !dir.as_os_str().is_empty() => dir,
+            (None, _) => Path::new("."),
+        }
+    }
+
+    /// Gets the directory a file should be encoded into, creating it if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The path to the file that's about to be encoded.
+    pub fn dir_for(&self, file: &Path) -> io::Result<PathBuf> {
+        let parent = self.location(file);
+        if self.shared.is_some() {
+            return Ok(parent.to_path_buf());
+        }
+        let mut hidden = self.hidden.lock().expect("poisoned");
+        if let Some(dir) = hidden.get(parent) {
+            return Ok(dir.path().to_path_buf());
+        }
+        let dir = Builder::new()
+            .prefix(discover::TMP_PREFIX)
+            .tempdir_in(parent)?;
+        let path = dir.path().to_path_buf();
+        crate::interrupt::remove_on_exit(path.clone());
+        hidden.insert(parent.to_path_buf(), dir);
+        Ok(path)
+    }
+
+    /// Waits until there's room for a job on the filesystem of `dir`, then holds that room
+    /// for it. Jobs that don't fit yet are held back until running ones finish.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory the job will write to.
+    /// * `bytes` - How much space the job needs.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Reservation)` once there's room.
+    /// * `Err(String)` if the job can't fit even with nothing else running.
+    pub fn reserve(&self, dir: &Path, bytes: u64) -> Result<Reservation<'_>, String> {
+        let device = device(dir).map_err(|e| format!("failed to read {}: {e}", dir.display()))?;
+        let mut reserved = self.reserved.lock().expect("poisoned");
+        loop {
+            let available = fs2::available_space(dir)
+                .map_err(|e| format!("failed to check free space in {}: {e}", dir.display()))?;
+            let held = reserved.get(&device).copied().unwrap_or(0);
+            if available >= held + bytes {
+                *reserved.entry(device).or_insert(0) += bytes;
+                return Ok(Reservation {
+                    workspace: self,
+                    device,
+                    bytes,
+                });
+            }
+            // nothing's running to free space up, so waiting won't help
+            if held == 0 {
+                return Err(format!(
+                    "not enough free space in {}: need {} but only {} is available",
+                    dir.display(),
+                    crate::humanize_bytes(bytes),
+                    crate::humanize_bytes(available)
+                ));
+            }
+            reserved = self.freed.wait(reserved).expect("poisoned");
+        }
+    }
+
+    /// Removes every directory the workspace made, along with anything left in them.
+    pub fn close(self) -> io::Result<()> {
+        if let Some(shared) = self.shared {
+            shared.close()?;
+        }
+        for (_, dir) in self.hidden.into_inner().expect("poisoned") {
+            dir.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// The path an encode is written to, which gets cleaned up once we're done with it.
+///
+/// If the encode was committed, the file has already been moved away and there's
+/// nothing to do. Otherwise it's a rejected, failed or trial encode, and it shouldn't
+/// hang around taking up space until the end of the run.
+pub struct Output(PathBuf);
+
+impl Output {
+    /// Names the output for a file inside a workspace directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The directory from `Workspace::dir_for`.
+    /// * `name` - The name of the encoded file.
+    pub fn new(dir: &Path, name: &Path) -> Self {
+        Self(dir.join(name))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut reserved = self.workspace.reserved.lock().expect("poisoned");
+        if let Some(held) = reserved.get_mut(&self.device) {
+            *held = held.saturating_sub(self.bytes);
+        }
+        self.workspace.freed.notify_all();
+    }
+}
+
+/// Checks how many of the files could be encoded at all, given the free space right now.
+///
+/// # Arguments
+///
+/// * `workspace` - Where the files will be encoded.
+/// * `files` - The files to check.
+///
+/// # Returns
+///
+/// * The files that are bigger than the free space where they'd be encoded.
+pub fn too_big<'a>(workspace: &Workspace, files: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+    files
+        .iter()
+        .filter(|file| {
+            let Ok(size) = std::fs::metadata(file).map(|m| m.len()) else {
+                return false;
+            };
+            fs2::available_space(workspace.location(file)).is_ok_and(|available| available < size)
+        })
+        .collect()
+}
+
+/// Identifies the filesystem a path is on.
+#[cfg(unix)]
+pub(crate) fn device(path: &Path) -> io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.dev())
+}
+
+/// Without device numbers, everything is treated as one filesystem, which only makes
+/// the space check more careful.
+#[cfg(not(unix))]
+pub(crate) fn device(path: &Path) -> io::Result<u64> {
+    std::fs::metadata(path).map(|_| 0)
+}